@@ -12,15 +12,15 @@ const INNER_BITS: usize = 1 << INNER_SHIFT;
 const INNER_MASK: usize = INNER_BITS - 1;
 
 pub struct AtomicBitMask {
-    bits: Box<Vec<AtomicType>>,
+    bits: Vec<AtomicType>,
 }
 
 impl AtomicBitMask {
     pub fn new(size: usize) -> AtomicBitMask {
         AtomicBitMask {
-            bits: Box::new(std::iter::repeat_with(|| AtomicType::new(0))
-                .take((size + INNER_BITS - 1)/INNER_BITS) // XXX a more clear way to allocate enough octets?
-                .collect::<Vec<_>>())
+            bits: std::iter::repeat_with(|| AtomicType::new(0))
+                .take(size.div_ceil(INNER_BITS))
+                .collect::<Vec<_>>()
         }
     }
 
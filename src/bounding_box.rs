@@ -1,7 +1,7 @@
 use std::fmt;
-use crate::points::{self, ColorPoint};
+use crate::{color_metric::ColorMetric, color_space::Coords, points::{self, ColorPoint}};
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub struct BoundingBox {
   // Lower RGB bounds
   pub lr: i32,
@@ -25,6 +25,14 @@ impl BoundingBox {
           self.ub < other.lb || other.ub < self.lb)
     }
 
+    /// Like intersects, but where the first axis wraps around every `period`
+    /// NB only we may hang off the ends of the axis, other must be within 0..period
+    pub fn intersects_wrapping(&self, other: &BoundingBox, period: i32) -> bool {
+        self.intersects(other) ||
+        self.shifted(period).intersects(other) ||
+        self.shifted(-period).intersects(other)
+    }
+
     pub fn contains(&self, other: &BoundingBox) -> bool {
         other.ur <= self.ur && other.lr >= self.lr &&
         other.ug <= self.ug && other.lg >= self.lg &&
@@ -38,6 +46,26 @@ impl BoundingBox {
         i32::from(color.b) >= self.lb && i32::from(color.b) <= self.ub
     }
 
+    #[inline]
+    pub fn contains_coords(&self, [c0, c1, c2]: &Coords) -> bool {
+        *c0 >= self.lr && *c0 <= self.ur &&
+        *c1 >= self.lg && *c1 <= self.ug &&
+        *c2 >= self.lb && *c2 <= self.ub
+    }
+
+    /// Like contains_coords, but where the first axis wraps around every `period`
+    #[inline]
+    pub fn contains_coords_wrapping(&self, [c0, c1, c2]: &Coords, period: i32) -> bool {
+        self.contains_coords(&[*c0, *c1, *c2]) ||
+        self.contains_coords(&[c0 + period, *c1, *c2]) ||
+        self.contains_coords(&[c0 - period, *c1, *c2])
+    }
+
+    /// A copy of us moved along the first axis
+    fn shifted(&self, by: i32) -> BoundingBox {
+        BoundingBox { lr: self.lr.saturating_add(by), ur: self.ur.saturating_add(by), ..*self }
+    }
+
     #[inline(never)]
     pub fn set_around(&mut self, center: &ColorPoint, radius: i32) {
         assert!(radius > 0, "Tried to set_around with a non-positive radius {radius}");
@@ -50,6 +78,25 @@ impl BoundingBox {
         self.ub = i32::from(center.b) + radius;
     }
 
+    /// Like set_around, but with a separate (possibly zero) radius on each channel
+    /// NB this works on coords, so the center may be in any color space
+    pub fn set_around_radii(&mut self, [c0, c1, c2]: &Coords, (rr, rg, rb): (i32, i32, i32)) {
+        assert!(rr >= 0 && rg >= 0 && rb >= 0, "Tried to set_around_radii with negative radii {rr}, {rg}, {rb}");
+
+        // NB saturate, as range searches may ask for everything with a huge radius
+        self.lr = c0.saturating_sub(rr);
+        self.ur = c0.saturating_add(rr);
+        self.lg = c1.saturating_sub(rg);
+        self.ug = c1.saturating_add(rg);
+        self.lb = c2.saturating_sub(rb);
+        self.ub = c2.saturating_add(rb);
+    }
+
+    /// Fits us around everything within `distance` of the center, as measured by the metric
+    pub fn set_around_metric(&mut self, center: &Coords, distance: i32, metric: &ColorMetric) {
+        self.set_around_radii(center, metric.radii(distance));
+    }
+
     /// Constructs a BoundingBox around the given center with the given radius
     pub fn from_around(center: &points::ColorPoint, radius: i32) -> BoundingBox {
         let mut bb = BoundingBox::new(0, 0, 0, 0, 0, 0);
@@ -76,6 +123,47 @@ impl BoundingBox {
         let ub = if !bp { self.ub - radius } else { self.ub };
         BoundingBox { lr, lg, lb, ur, ug, ub }
     }
+
+    /// Constructs the child of this bounding box for some octree index by cutting it in half on each axis
+    /// Unlike sub_for_idx this works for any (non-cubic, non-power-of-two) box
+    pub fn half_for_idx(&self, index: usize) -> BoundingBox {
+        // Subdivision packing is RGB ---, --+, -+-, -++, +--, +-+, ++-, +++
+        assert!(index < 8, "Tried to get half bounding box for index {} (must be < 8)", index);
+
+        let [mr, mg, mb] = self.midpoint();
+        let rp = index & 0b100 != 0;
+        let gp = index & 0b010 != 0;
+        let bp = index & 0b001 != 0;
+
+        let (lr, ur) = if rp { (mr, self.ur) } else { (self.lr, mr - 1) };
+        let (lg, ug) = if gp { (mg, self.ug) } else { (self.lg, mg - 1) };
+        let (lb, ub) = if bp { (mb, self.ub) } else { (self.lb, mb - 1) };
+        BoundingBox { lr, lg, lb, ur, ug, ub }
+    }
+
+    /// Cuts us in two across the given axis (0, 1 or 2) at `at`, like a k-d tree node does
+    /// NB both halves keep `at` itself, so points sitting on the split can go either way
+    pub fn split(&self, axis: usize, at: i32) -> (BoundingBox, BoundingBox) {
+        let (mut lower, mut upper) = (*self, *self);
+        match axis {
+            0 => { lower.ur = at; upper.lr = at; }
+            1 => { lower.ug = at; upper.lg = at; }
+            2 => { lower.ub = at; upper.lb = at; }
+            _ => panic!("Tried to split a bounding box on axis {axis} (must be < 3)"),
+        }
+
+        (lower, upper)
+    }
+
+    /// The first coordinate of the upper half on each axis
+    #[inline]
+    pub fn midpoint(&self) -> Coords {
+        [
+            self.lr + (self.ur - self.lr + 1) / 2,
+            self.lg + (self.ug - self.lg + 1) / 2,
+            self.lb + (self.ub - self.lb + 1) / 2,
+        ]
+    }
 }
 
 impl fmt::Display for BoundingBox {
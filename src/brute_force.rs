@@ -0,0 +1,113 @@
+use parking_lot::RwLock;
+
+use crate::{points::{SpacePoint, Point, ColorPoint}, color_metric::ColorMetric, color_space::ColorSpace, nn_search_3d::NnSearch3d, octree_leafy::LeafEntry};
+
+/// Checks every point on every search, far too slow for real use but obviously right
+/// This is what we hold the other NnSearch3d implementations up against
+pub struct BruteForce {
+    points: RwLock<Vec<LeafEntry>>,
+    space: ColorSpace,
+    metric: ColorMetric,
+}
+
+impl BruteForce {
+    pub fn new() -> BruteForce {
+        Self::new_in(ColorSpace::default(), ColorMetric::default())
+    }
+
+    pub fn new_in(space: ColorSpace, metric: ColorMetric) -> BruteForce {
+        BruteForce { points: RwLock::new(Vec::new()), space, metric }
+    }
+
+    /// How far a color is from the target, in our space and metric
+    pub fn distance(&self, a: &ColorPoint, b: &ColorPoint) -> i32 {
+        self.space.distance(&self.metric, &self.space.coords(a), &self.space.coords(b))
+    }
+}
+
+impl Default for BruteForce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NnSearch3d for BruteForce {
+    fn has(&self, pt: &SpacePoint) -> bool {
+        self.points.read().iter().any(|e| e.point.space() == pt)
+    }
+
+    fn has_point(&self, pt: &Point) -> bool {
+        self.points.read().iter().any(|e| &e.point == pt)
+    }
+
+    fn len(&self) -> usize {
+        self.points.read().len()
+    }
+
+    fn add(&self, point: Point, _spare_vectors: &mut Vec<Vec<Point>>) {
+        let mut points = self.points.write();
+        if !points.iter().any(|e| e.point == point) {
+            points.push(LeafEntry { point, coords: self.space.coords(point.color()) });
+        }
+    }
+
+    fn remove(&self, point: Point, _spare_vectors: &mut Vec<Vec<Point>>) {
+        self.points.write().retain(|e| e.point != point);
+    }
+
+    fn find_nearest(&self, color: &ColorPoint) -> Option<Point> {
+        let coords = self.space.coords(color);
+
+        self.points.read()
+            .iter()
+            .min_by_key(|e| self.space.distance(&self.metric, &e.coords, &coords))
+            .map(|e| e.point)
+    }
+
+    fn find_k_nearest(&self, color: &ColorPoint, k: usize) -> Vec<Point> {
+        let coords = self.space.coords(color);
+        let mut by_distance = self.points.read()
+            .iter()
+            .map(|e| (self.space.distance(&self.metric, &e.coords, &coords), e.point))
+            .collect::<Vec<_>>();
+
+        by_distance.sort_unstable();
+        by_distance.into_iter().take(k).map(|(_, point)| point).collect()
+    }
+
+    fn find_within(&self, color: &ColorPoint, radius_sq: i32) -> Vec<Point> {
+        let coords = self.space.coords(color);
+
+        self.points.read()
+            .iter()
+            .filter(|e| self.space.distance(&self.metric, &e.coords, &coords) <= radius_sq)
+            .map(|e| e.point)
+            .collect()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test]
+fn test_brute_force() {
+    let tree = BruteForce::new();
+    let mut spare_vectors = Vec::new();
+    assert_eq!(tree.find_nearest(&ColorPoint::new(0, 0, 0)), None);
+
+    let dark = Point::new(SpacePoint::new(0, 0), ColorPoint::new(10, 10, 10));
+    let light = Point::new(SpacePoint::new(0, 0), ColorPoint::new(240, 240, 240));
+    tree.add(dark, &mut spare_vectors);
+    tree.add(light, &mut spare_vectors);
+    tree.add(dark, &mut spare_vectors);
+    assert_eq!(tree.len(), 2);
+
+    assert_eq!(tree.find_nearest(&ColorPoint::new(0, 0, 0)), Some(dark));
+    assert_eq!(tree.find_nearest(&ColorPoint::new(200, 200, 200)), Some(light));
+
+    tree.remove(dark, &mut spare_vectors);
+    assert!(tree.has(dark.space()), "The light point still sits there");
+    assert!(!tree.has_point(&dark));
+    assert_eq!(tree.find_nearest(&ColorPoint::new(0, 0, 0)), Some(light));
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+
+use crate::points::SpacePoint;
+
+/// The largest width or height we can pack into a SpacePoint
+pub const MAX_DIMENSION: u32 = 1 << 16;
+
+/// The dimensions of the image we are growing into
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Canvas {
+        assert!(width > 0 && height > 0, "Tried to make an empty {width}x{height} canvas");
+        assert!(
+            width <= MAX_DIMENSION && height <= MAX_DIMENSION,
+            "Tried to make a {width}x{height} canvas (dimensions must be <= {MAX_DIMENSION})"
+        );
+
+        Canvas { width, height }
+    }
+
+    /// Number of pixels on the canvas
+    pub fn size(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height
+    }
+
+    /// Converts an x/y coordinate to an index into our image buffers and masks
+    pub fn offset(&self, x: u32, y: u32) -> usize {
+        debug_assert!(self.contains(x, y), "Tried to index {x},{y} outside of a {}x{} canvas", self.width, self.height);
+        y as usize * self.width as usize + x as usize
+    }
+
+    /// Converts a space point to an index into our image buffers and masks
+    pub fn space_offset(&self, space: &SpacePoint) -> usize {
+        let (x, y) = space.xy();
+        self.offset(x, y)
+    }
+
+    /// Inverse of `offset`
+    pub fn space_at(&self, offset: usize) -> SpacePoint {
+        let width = self.width as usize;
+        SpacePoint::new((offset % width) as u32, (offset / width) as u32)
+    }
+}
+
+impl fmt::Display for Canvas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Canvas<{}x{}>", self.width, self.height)
+    }
+}
+
+#[test]
+fn test_canvas_offsets_round_trip() {
+    let canvas = Canvas::new(2048, 8192);
+    assert_eq!(canvas.size(), 2048 * 8192);
+
+    for (x, y) in [(0, 0), (2047, 0), (0, 8191), (2047, 8191), (1000, 5000)] {
+        let space = SpacePoint::new(x, y);
+        let offset = canvas.space_offset(&space);
+        assert!(offset < canvas.size());
+        assert_eq!(canvas.space_at(offset), space);
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use bitvec::prelude::*;
+
+use crate::{canvas::Canvas, image::read_png, points::SpacePoint};
+
+/// Which pixels of a canvas we are allowed to grow into
+#[derive(Clone, Debug, PartialEq)]
+pub struct CanvasMask {
+    canvas: Canvas,
+    fillable: BitVec<usize, Msb0>,
+}
+
+impl CanvasMask {
+    /// Everything fillable to start with
+    pub fn new(canvas: Canvas) -> CanvasMask {
+        CanvasMask { canvas, fillable: bitvec![usize, Msb0; 1; canvas.size()] }
+    }
+
+    /// Asks the predicate whether each x, y should be fillable
+    pub fn from_fn(canvas: Canvas, fillable: impl Fn(u32, u32) -> bool) -> CanvasMask {
+        let mut mask = CanvasMask::new(canvas);
+        for offset in 0..canvas.size() {
+            let (x, y) = canvas.space_at(offset).xy();
+            mask.fillable.set(offset, fillable(x, y));
+        }
+
+        mask
+    }
+
+    /// Reads a mask from an image, where light opaque pixels are fillable
+    /// Dark or transparent pixels are blocked
+    pub fn read(path: &Path) -> Result<CanvasMask, png::DecodingError> {
+        let (canvas, pixels) = read_png(path)?;
+
+        let mut mask = CanvasMask::new(canvas);
+        for (offset, [r, g, b, a]) in pixels.into_iter().enumerate() {
+            mask.fillable.set(offset, a >= 128 && r.max(g).max(b) >= 128);
+        }
+
+        Ok(mask)
+    }
+
+    pub fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, fillable: bool) {
+        assert!(self.canvas.contains(x, y), "Tried to mask {x},{y} outside of {}", self.canvas);
+        self.fillable.set(self.canvas.offset(x, y), fillable);
+    }
+
+    pub fn is_fillable(&self, x: u32, y: u32) -> bool {
+        self.canvas.contains(x, y) && self.fillable[self.canvas.offset(x, y)]
+    }
+
+    pub fn is_fillable_space(&self, space: &SpacePoint) -> bool {
+        let (x, y) = space.xy();
+        self.is_fillable(x, y)
+    }
+
+    /// The most pixels we could possibly fill
+    pub fn fillable_count(&self) -> usize {
+        self.fillable.count_ones()
+    }
+}
+
+#[test]
+fn test_canvas_mask() {
+    let canvas = Canvas::new(10, 10);
+
+    // A little disc
+    let mut mask = CanvasMask::from_fn(canvas, |x, y| (x as i32 - 5).pow(2) + (y as i32 - 5).pow(2) <= 9);
+    assert!(mask.is_fillable(5, 5));
+    assert!(mask.is_fillable(5, 2));
+    assert!(!mask.is_fillable(0, 0));
+    assert!(!mask.is_fillable(50, 5), "Outside the canvas is never fillable");
+    assert_eq!(mask.fillable_count(), 29);
+
+    mask.set(5, 5, false);
+    assert!(!mask.is_fillable_space(&SpacePoint::new(5, 5)));
+    assert_eq!(mask.fillable_count(), 28);
+}
+
+#[test]
+fn test_read_canvas_mask() {
+    let path = crate::seed_map::write_test_png("canvas_mask", 2, 2, &[
+        [255, 255, 255, 255], [0, 0, 0, 255],
+        [255, 255, 255, 0], [200, 10, 10, 255],
+    ]);
+
+    let mask = CanvasMask::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(mask.canvas(), &Canvas::new(2, 2));
+    assert!(mask.is_fillable(0, 0));
+    assert!(!mask.is_fillable(1, 0), "Dark pixels are blocked");
+    assert!(!mask.is_fillable(0, 1), "Transparent pixels are blocked");
+    assert!(mask.is_fillable(1, 1));
+}
@@ -1,77 +1,456 @@
 use std::collections::HashMap;
+use std::{error, fmt};
 use std::sync::{Arc, mpsc};
 use std::thread;
 use std::time::{Instant};
 use std::{path::Path};
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufWriter, Write};
 use log::{trace};
 
-use bitvec::prelude::*;
+use fnv::FnvHashMap;
 use spmc::Receiver;
+use std::sync::mpsc::TryRecvError;
 
 
+use crate::canvas::{Canvas, MAX_DIMENSION};
+use crate::canvas_mask::CanvasMask;
+use crate::color_metric::ColorMetric;
+use crate::color_ordering::{ColorOrdering, Shuffled};
+use crate::color_space::ColorSpace;
 use crate::image::Image;
-use crate::nn_search_3d::NnSearch3d;
-use crate::octree_leafy::OctreeLeafy;
+use crate::nn_search_3d::{NnSearch3d, SearchBackend};
+use crate::octree_leafy::TieBreak;
+use crate::palette::{palette_of_size, repeated_palette, FULL_PALETTE_SIZE};
+use crate::seed_layout::SeedLayout;
+use crate::seed_map::{SeedMap, SeedMapError};
+use crate::space_mask::SpaceMask;
+use crate::topology::{GridTopology, HexTopology, Topology};
+use crate::volume::Volume;
 use crate::{points::{ColorPoint, SpacePoint, Point}};
 
-type SpacePoints = Box<Vec<SpacePoint>>;
-
 pub struct ColorGenerator {
+  canvas: Canvas,
   colors: Vec<ColorPoint>,
-  spaces: SpacePoints,
-  writing_spaces: Box<[usize]>,
-  written_spaces: Box<[usize]>,
-  root: Arc<OctreeLeafy>,
+  writing_spaces: SpaceMask,
+  written_spaces: SpaceMask,
+  root: Arc<dyn NnSearch3d + Send + Sync>,
+  // What our root is built from
+  backend: SearchBackend,
+  color_space: ColorSpace,
+  metric: ColorMetric,
+  tie_break: TieBreak,
   image: Image,
   current_color_idx: usize,
   space_mapping: HashMap<SpacePoint, Vec<ColorPoint>>,
+  // Our own rng so a render can be reproduced from its seed
+  rng: fastrand::Rng,
+  seed: u64,
+  // Where each copy of a color sits in our palette, only built once someone seeds an explicit color
+  color_positions: Option<ColorPositions>,
+  mask: Option<CanvasMask>,
+  topology: Box<dyn Topology>,
 }
 
-fn make_boxed_bit_array() -> Box<[usize]> {
-  // Silliness to start it on the heap
-  vec![0usize; 4096 * 4096 / std::mem::size_of::<usize>()].into_boxed_slice()
+/// Every palette index grouped by the color there, so a color's copies are one run we can find from its start
+/// NB one flat list rather than a list per color, as big canvases have millions of colors
+struct ColorPositions {
+  run_starts: FnvHashMap<ColorPoint, usize>,
+  indices: Vec<usize>,
 }
 
+impl ColorPositions {
+  fn new(colors: &[ColorPoint]) -> ColorPositions {
+    let mut indices = (0..colors.len()).collect::<Vec<_>>();
+    indices.sort_by_key(|&idx| colors[idx]);
+
+    let mut run_starts = FnvHashMap::default();
+    for (slot, &idx) in indices.iter().enumerate() {
+      run_starts.entry(colors[idx]).or_insert(slot);
+    }
+
+    ColorPositions { run_starts, indices }
+  }
+
+  /// The slots in `indices` holding each copy of the color, if the palette has it at all
+  fn run(&self, colors: &[ColorPoint], color: &ColorPoint) -> Option<std::ops::Range<usize>> {
+    let start = *self.run_starts.get(color)?;
+    let len = self.indices[start..].iter().take_while(|&&idx| colors[idx] == *color).count();
+
+    Some(start..start + len)
+  }
+}
+
+/// Why we couldn't place an explicitly colored seed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SeedError {
+  OutOfBounds { x: u32, y: u32 },
+  PixelWritten { x: u32, y: u32 },
+  Masked { x: u32, y: u32 },
+  ColorUsed(ColorPoint),
+  NotInPalette(ColorPoint),
+}
+
+impl fmt::Display for SeedError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SeedError::OutOfBounds { x, y } => write!(f, "Seed {x},{y} is outside the canvas"),
+      SeedError::PixelWritten { x, y } => write!(f, "Seed {x},{y} has already been written"),
+      SeedError::Masked { x, y } => write!(f, "Seed {x},{y} is masked off"),
+      SeedError::ColorUsed(color) => write!(f, "Seed {color} has already been used"),
+      SeedError::NotInPalette(color) => write!(f, "Seed {color} is not in the palette"),
+    }
+  }
+}
+
+impl error::Error for SeedError {}
+
 // Public things
 impl ColorGenerator {
-  pub fn new() -> ColorGenerator {
+  pub fn new(width: u32, height: u32) -> ColorGenerator {
+    let canvas = Canvas::new(width, height);
+    // Canvases bigger than the color cube use every color the same number of times
+    let multiplicity = canvas.size().div_ceil(FULL_PALETTE_SIZE);
+    // Use every color of a palette sized to fit the canvas, where we can
+    let colors = repeated_palette(palette_of_size(canvas.size().div_ceil(multiplicity)), multiplicity);
+
+    ColorGenerator::new_on(canvas, Image::new(canvas), colors)
+  }
+
+  /// A generator on the biggest canvas we can address, MAX_DIMENSION on each side, that spreads out until the palette runs out
+  /// Only what we actually paint is stored, so seed near the middle (e.g. `center`) and
+  /// `write_image` will crop down to whatever got painted
+  /// NB the edges are still hard, growth that reaches them stops there like on any other canvas
+  pub fn new_sparse() -> ColorGenerator {
+    let canvas = Canvas::new(MAX_DIMENSION, MAX_DIMENSION);
+
+    ColorGenerator::new_on(canvas, Image::new_sparse(canvas), palette_of_size(FULL_PALETTE_SIZE))
+  }
+
+  /// Everything but the canvas, image and palette starts out the same, and the masks follow the image's storage
+  fn new_on(canvas: Canvas, image: Image, colors: Vec<ColorPoint>) -> ColorGenerator {
+    // Something new every run unless told otherwise
+    let seed = fastrand::u64(..);
+    let (writing_spaces, written_spaces) = if image.is_sparse() {
+      (SpaceMask::sparse(), SpaceMask::sparse())
+    } else {
+      (SpaceMask::dense(canvas), SpaceMask::dense(canvas))
+    };
+
     ColorGenerator {
-      colors: initialize_color_space(),
-      spaces: initialize_space_space(),
+      canvas,
+      colors,
       current_color_idx: 0,
-      image: Image::new(),
-      writing_spaces: make_boxed_bit_array(),
-      written_spaces: make_boxed_bit_array(),
+      image,
+      writing_spaces,
+      written_spaces,
       //root: Octree::new(None, 0, 0, BoundingBox::new(0, 0, 0, 255, 255, 255)),
-      root: OctreeLeafy::init_tree(4).into(),
+      root: SearchBackend::default().build(ColorSpace::default(), ColorMetric::default(), TieBreak::default()),
+      backend: SearchBackend::default(),
+      color_space: ColorSpace::default(),
+      metric: ColorMetric::default(),
+      tie_break: TieBreak::default(),
       space_mapping: HashMap::new(),
+      rng: fastrand::Rng::with_seed(seed),
+      seed,
+      color_positions: None,
+      mask: None,
+      topology: Box::new(GridTopology::default()),
+    }
+  }
+
+  /// The middle of our canvas, a good place to seed a sparse generator
+  pub fn center(&self) -> (u32, u32) {
+    (self.canvas.width / 2, self.canvas.height / 2)
+  }
+
+  /// Restarts our rng from the given seed, so the same settings will give the same image
+  pub fn set_seed(&mut self, seed: u64) {
+    self.rng.seed(seed);
+    self.seed = seed;
+  }
+
+  /// The seed we were created with or last given, for reproducing a run later
+  pub fn seed(&self) -> u64 {
+    self.seed
+  }
+
+  /// Replaces the palette we draw colors from, must happen before any seeding
+  pub fn set_palette(&mut self, colors: Vec<ColorPoint>) {
+    assert!(self.current_color_idx == 0, "Tried to set the palette after seeding");
+    assert!(!colors.is_empty(), "Tried to set an empty palette");
+
+    self.colors = colors;
+    self.color_positions = None;
+  }
+
+  /// Uses each color of a palette sized to fit the canvas exactly `multiplicity` times, must happen before any seeding
+  /// e.g. 4 on an 8192x8192 canvas uses every 24-bit color 4 times
+  pub fn set_multiplicity(&mut self, multiplicity: usize) {
+    assert!(multiplicity > 0, "Tried to use each color 0 times");
+
+    let distinct = self.canvas.size().div_ceil(multiplicity);
+    assert!(
+      distinct <= FULL_PALETTE_SIZE,
+      "Tried to use each color {multiplicity} times on {}, which needs {distinct} colors", self.canvas
+    );
+
+    self.set_palette(repeated_palette(palette_of_size(distinct), multiplicity));
+  }
+
+  pub fn palette_len(&self) -> usize {
+    self.colors.len()
+  }
+
+  /// Switches how we measure color distances, must happen before any seeding
+  pub fn set_metric(&mut self, metric: ColorMetric) {
+    assert!(self.root.is_empty(), "Tried to set the metric after seeding");
+
+    self.metric = metric;
+    self.rebuild_root();
+  }
+
+  /// Switches which color space we search for nearest colors in, must happen before any seeding
+  /// The image is still painted in sRGB
+  pub fn set_color_space(&mut self, space: ColorSpace) {
+    assert!(self.root.is_empty(), "Tried to set the color space after seeding");
+
+    self.color_space = space;
+    self.rebuild_root();
+  }
+
+  /// Switches which NnSearch3d we keep the frontier in, must happen before any seeding
+  pub fn set_search_backend(&mut self, backend: SearchBackend) {
+    assert!(self.root.is_empty(), "Tried to set the search backend after seeding");
+    assert!(
+      backend.supports(&self.tie_break),
+      "Tried to switch to the {backend:?} backend while breaking ties by {:?}, set the tie break back to FirstFound first", self.tie_break
+    );
+
+    self.backend = backend;
+    self.rebuild_root();
+  }
+
+  /// Switches which of several equally near frontier spaces a color goes to, must happen before any seeding
+  /// Anything but FirstFound needs the octree backend
+  pub fn set_tie_break(&mut self, tie_break: TieBreak) {
+    assert!(self.root.is_empty(), "Tried to set the tie break after seeding");
+    assert!(
+      self.backend.supports(&tie_break),
+      "Tried to break ties by {tie_break:?} with the {:?} backend, only the octree can do that", self.backend
+    );
+
+    self.tie_break = tie_break;
+    self.rebuild_root();
+  }
+
+  fn rebuild_root(&mut self) {
+    self.root = self.backend.build(self.color_space, self.metric, self.tie_break);
+  }
+
+  /// Only lets us grow into the mask's fillable pixels, must happen before any seeding
+  pub fn set_mask(&mut self, mask: CanvasMask) {
+    assert!(self.current_color_idx == 0, "Tried to set the mask after seeding");
+    assert!(mask.canvas() == &self.canvas, "Tried to use a mask for {} on {}", mask.canvas(), self.canvas);
+    assert!(!self.writing_spaces.is_sparse(), "Tried to mask a sparse generator");
+
+    // NB masked pixels are just claimed up front, so the frontier never reaches them
+    for ofs in 0..self.canvas.size() {
+      let space = self.canvas.space_at(ofs);
+      let fillable = mask.is_fillable_space(&space);
+      self.writing_spaces.set(&space, !fillable);
+      self.written_spaces.set(&space, !fillable);
     }
+
+    self.mask = Some(mask);
+  }
+
+  /// Changes which pixels count as neighbors, must happen before any seeding
+  pub fn set_topology(&mut self, topology: impl Topology + 'static) {
+    assert!(self.root.is_empty(), "Tried to set the topology after seeding");
+
+    self.topology = Box::new(topology);
   }
 
   pub fn shuffle_colors(&mut self) {
-    fastrand::seed(0); // Testing
-    fastrand::shuffle(&mut self.colors);
+    self.order_colors(&Shuffled);
   }
 
-  pub fn add_next_seed_pixel(&mut self, x: u32, y: u32, point_pool: &mut Vec<Vec<Point>>) {
-    let color = self.colors[self.current_color_idx].clone();
-    self.current_color_idx += 1;
-    let ofs = space_offset(x, y);
+  /// Rearranges the palette into the order we will place it in, must happen before any seeding
+  pub fn order_colors(&mut self, ordering: &dyn ColorOrdering) {
+    assert!(self.current_color_idx == 0, "Tried to order the colors after seeding");
 
-    println!("Seed is {ofs}");
+    ordering.order(&mut self.colors, &self.rng);
+    self.color_positions = None;
+  }
 
-    if self.writing_spaces.view_bits::<Msb0>()[ofs] || self.written_spaces.view_bits::<Msb0>()[ofs] {
+  pub fn add_next_seed_pixel(&mut self, x: u32, y: u32, point_pool: &mut Vec<Vec<Point>>) {
+    assert!(self.canvas.contains(x, y), "Tried to seed {x},{y} outside of {}", self.canvas);
+    assert!(!self.is_masked(x, y), "Tried to seed {x},{y} which is masked off");
+
+    if self.is_claimed(&SpacePoint::new(x, y)) {
       panic!("Seeded already written point");
     }
 
-    self.writing_spaces.view_bits_mut::<Msb0>().set(ofs, true);
-    self.written_spaces.view_bits_mut::<Msb0>().set(ofs, true);
+    self.place_seed(x, y, point_pool);
+  }
 
-    // Write out the pixel
-    let space = self.spaces[ofs].clone();
-    self.image.write(&space, &color);
+  /// Seeds a position with a particular color, which we take out of the remaining palette
+  pub fn add_seed_pixel(&mut self, x: u32, y: u32, color: ColorPoint, point_pool: &mut Vec<Vec<Point>>) -> Result<(), SeedError> {
+    if !self.canvas.contains(x, y) {
+      return Err(SeedError::OutOfBounds { x, y });
+    }
+
+    if self.is_masked(x, y) {
+      return Err(SeedError::Masked { x, y });
+    }
+
+    if self.is_claimed(&SpacePoint::new(x, y)) {
+      return Err(SeedError::PixelWritten { x, y });
+    }
+
+    self.make_next_color(color)?;
+    self.place_seed(x, y, point_pool);
+
+    Ok(())
+  }
+
+  /// Marks the map's pixels as already painted in their own colors, and grows around them
+  /// With `remove_from_palette` we also use up those colors where the palette still has them
+  /// Returns how many pixels we froze
+  pub fn add_frozen_pixels(&mut self, map: &SeedMap, remove_from_palette: bool, point_pool: &mut Vec<Vec<Point>>) -> Result<usize, SeedMapError> {
+    if map.canvas() != &self.canvas {
+      return Err(SeedMapError::SizeMismatch { expected: self.canvas, found: *map.canvas() });
+    }
+
+    // Check everything up front so we don't freeze half an image
+    for &(x, y, _) in map.seeds() {
+      if self.is_masked(x, y) {
+        return Err(SeedError::Masked { x, y }.into());
+      }
+
+      if self.is_claimed(&SpacePoint::new(x, y)) {
+        return Err(SeedError::PixelWritten { x, y }.into());
+      }
+    }
+
+    // Claim everything before adding any neighbors, so the frontier stays outside the frozen area
+    for &(x, y, color) in map.seeds() {
+      self.claim_pixel(&SpacePoint::new(x, y), &color, point_pool);
+
+      // NB colors not in the palette (or repeated more often than the palette has them) just don't use anything up
+      if remove_from_palette && self.make_next_color(color).is_ok() {
+        self.current_color_idx += 1;
+      }
+    }
+
+    let mut add_vec = Vec::with_capacity(4);
+    for &(x, y, color) in map.seeds() {
+      self.add_neighbors(&SpacePoint::new(x, y), &color, &mut add_vec, point_pool);
+    }
+
+    Ok(map.seeds().len())
+  }
+
+  /// Swaps the given color up to be the next one we'll use, like the C++ version
+  fn make_next_color(&mut self, color: ColorPoint) -> Result<(), SeedError> {
+    let colors = &self.colors;
+    let positions = self.color_positions.get_or_insert_with(|| ColorPositions::new(colors));
+
+    let Some(run) = positions.run(colors, &color) else {
+      return Err(SeedError::NotInPalette(color));
+    };
+
+    // Any copy we haven't used yet will do
+    let next_idx = self.current_color_idx;
+    let Some(slot) = run.into_iter().find(|&slot| positions.indices[slot] >= next_idx) else {
+      return Err(SeedError::ColorUsed(color));
+    };
+
+    // Find where the color we're displacing is listed before the swap changes what's where
+    let idx = positions.indices[slot];
+    let displaced_slot = positions.run(colors, &colors[next_idx]).unwrap()
+      .find(|&slot| positions.indices[slot] == next_idx)
+      .unwrap();
+
+    positions.indices[slot] = next_idx;
+    positions.indices[displaced_slot] = idx;
+    self.colors.swap(idx, next_idx);
+
+    Ok(())
+  }
+
+  /// Seeds every position in the layout with the next colors, skipping any already written
+  /// Returns how many seeds we placed
+  pub fn add_seed_layout(&mut self, layout: &SeedLayout, point_pool: &mut Vec<Vec<Point>>) -> usize {
+    let positions = layout.positions(&self.canvas, &self.rng);
+    let mut placed = 0;
+
+    for (x, y) in positions {
+      if self.is_claimed(&SpacePoint::new(x, y)) {
+        continue;
+      }
+
+      assert!(self.current_color_idx < self.colors.len(), "Tried to seed {x},{y} but we are out of colors");
+      self.place_seed(x, y, point_pool);
+      placed += 1;
+    }
+
+    placed
+  }
+
+  /// Seeds every pixel of the map with its own color, taking those colors out of the palette
+  /// With `snap_to_palette` each color is swapped for the nearest one we haven't used yet, which lets
+  /// painted maps seed palettes that are only a subset of the color cube (i.e. smaller canvases)
+  /// Stops at the first seed we can't place, but any before it stay placed
+  /// Returns how many seeds we placed
+  pub fn add_seed_map(&mut self, map: &SeedMap, snap_to_palette: bool, point_pool: &mut Vec<Vec<Point>>) -> Result<usize, SeedMapError> {
+    if map.canvas() != &self.canvas {
+      return Err(SeedMapError::SizeMismatch { expected: self.canvas, found: *map.canvas() });
+    }
+
+    for &(x, y, color) in map.seeds() {
+      let color = if snap_to_palette { self.nearest_unused_color(color) } else { color };
+      self.add_seed_pixel(x, y, color, point_pool)?;
+    }
+
+    Ok(map.seeds().len())
+  }
+
+  /// The closest color to this one we could still seed with, or the color itself if we have none left
+  /// NB anything but an exact match scans the rest of the palette, so this is only for seeding
+  fn nearest_unused_color(&mut self, color: ColorPoint) -> ColorPoint {
+    // Moving it up to next doesn't hurt, it's what seeding it does anyway
+    if self.make_next_color(color).is_ok() {
+      return color;
+    }
+
+    let target = self.color_space.coords(&color);
+    self.colors[self.current_color_idx..]
+      .iter()
+      .min_by_key(|candidate| self.color_space.distance(&self.metric, &target, &self.color_space.coords(candidate)))
+      .copied()
+      .unwrap_or(color)
+  }
+
+  fn is_masked(&self, x: u32, y: u32) -> bool {
+    self.mask.as_ref().is_some_and(|mask| !mask.is_fillable(x, y))
+  }
+
+  fn is_claimed(&self, space: &SpacePoint) -> bool {
+    self.writing_spaces.test(space) || self.written_spaces.test(space)
+  }
+
+  /// Puts the next color at x, y, which must be on the canvas and unclaimed
+  fn place_seed(&mut self, x: u32, y: u32, point_pool: &mut Vec<Vec<Point>>) {
+    let color = self.colors[self.current_color_idx];
+    self.current_color_idx += 1;
+
+    let space = SpacePoint::new(x, y);
+
+    println!("Seed is {space}");
+
+    self.claim_pixel(&space, &color, point_pool);
 
     // Add our initial neighbors
     let mut add_vec = Vec::with_capacity(4);
@@ -79,17 +458,39 @@ impl ColorGenerator {
 
   }
 
+  /// Paints a pixel outside of the usual growth, making sure nothing else will grow into it
+  fn claim_pixel(&mut self, space: &SpacePoint, color: &ColorPoint, point_pool: &mut Vec<Vec<Point>>) {
+    self.writing_spaces.set(space, true);
+    self.written_spaces.set(space, true);
+
+    // Write out the pixel
+    self.image.write(space, color);
+
+    // We might have been next to an earlier seed, so we're not available any more
+    if let Some(stale) = self.space_mapping.remove(space) {
+      for stale_color in stale {
+        self.root.remove(Point::new(*space, stale_color), point_pool);
+      }
+    }
+  }
+
   fn add_neighbors(&mut self, space: &SpacePoint, color: &ColorPoint, add_vec: &mut Vec<SpacePoint>, point_pool: &mut Vec<Vec<Point>>) {
-    space.get_neighbors(add_vec);
+    self.topology.neighbors(space, &self.canvas, add_vec);
     for neighbor in add_vec {
-      if self.written_spaces.view_bits::<Msb0>()[neighbor.offset()] || self.writing_spaces.view_bits::<Msb0>()[neighbor.offset()] {
+      if self.is_claimed(neighbor) {
         // Already occupied
         continue;
       } else {
         let new_point = Point::new(*neighbor, *color);
 
-        self.space_mapping.entry(neighbor.clone()).or_insert(Vec::new()).push(color.clone());
-        println!("Adding {new_point} (seed)");
+        // NB with repeated colors a neighbor might already be waiting on this one
+        let waiting = self.space_mapping.entry(*neighbor).or_default();
+        if waiting.contains(color) {
+          continue;
+        }
+
+        waiting.push(*color);
+        trace!("Adding {new_point} (seed)");
         self.root.add(new_point, point_pool)
       }
     }
@@ -106,10 +507,13 @@ impl ColorGenerator {
     // Worker threads also report their performance metrics somehow???
     // Just gotta keep the book-keeping performant
     
-    if self.current_color_idx == 0 {
+    if self.current_color_idx == 0 && self.space_mapping.is_empty() {
       panic!("Tried to call grow_pixels_to without any seed pixels");
     }
 
+    assert!(pixel_count <= self.canvas.size(), "Tried to grow {pixel_count} pixels on {}", self.canvas);
+    assert!(pixel_count <= self.colors.len(), "Tried to grow {pixel_count} pixels with only {} colors", self.colors.len());
+
     println!("Start of the party with {} existing", self.root.len());
 
     // Diagnostic timers, these are all in microseconds
@@ -160,11 +564,15 @@ impl ColorGenerator {
             next
           } else {
             // If we do have some backfill, try the receiver first but then use the backfill
-            let Some(next) = rx_search_send.try_recv().ok().or_else(|| backfill.pop()) else {
-              continue;
-            };
-
-            next
+            match rx_search_send.try_recv() {
+              Ok(next) => next,
+              Err(TryRecvError::Empty) => backfill.pop().unwrap(),
+              Err(TryRecvError::Disconnected) => {
+                // We're done even if we couldn't place these, the main thread will take them back
+                println!("Search thread {} exiting with {} unplaced", thread_id, backfill.len());
+                break;
+              }
+            }
           };
 
           // Search for the next point
@@ -186,6 +594,8 @@ impl ColorGenerator {
             }
           }
         }
+
+        backfill
       }).unwrap());
     }
 
@@ -198,7 +608,7 @@ impl ColorGenerator {
       mutation_handles.push(thread::Builder::new().name(format!("Mutator {}", thread_id)).spawn(move || {
 
         // Our own point pool
-        let mut point_pool = vec![Vec::with_capacity(8); 1024];
+        let mut point_pool: Vec<Vec<Point>> = (0..1024).map(|_| Vec::with_capacity(8)).collect();
 
         loop {
           // Wait for a result to mutate
@@ -234,14 +644,19 @@ impl ColorGenerator {
     // Basically just start dispatching work and updating stats
     while self.current_color_idx < pixel_count || outstanding > 0 || !color_collisions.is_empty() {
 
+      if self.space_mapping.is_empty() {
+        // Nowhere left to grow, e.g. we filled everything we can reach inside a mask
+        println!("Out of places to grow with {outstanding} colors in flight");
+        break;
+      }
+
       // Dispatch a handful of colors
       if outstanding < self.space_mapping.len() + 16 && self.current_color_idx < pixel_count {
         for _ in 0..16 {
           
           // Take either one of our collisions or the next color
-          let color = color_collisions.pop().map(|c| {
+          let color = color_collisions.pop().inspect(|&c| {
             trace!("Dispatching {c} from collision list");
-            c
           }).unwrap_or_else(|| {
             let color_idx = self.current_color_idx;
             self.current_color_idx += 1;
@@ -254,10 +669,10 @@ impl ColorGenerator {
               // Progress
               let time_so_far = wall_start_time.elapsed().as_micros();
               let time_per_px = time_so_far as f64 / i as f64;
-              let remaining = time_per_px * (4096 * 4096 - i) as f64;
+              let remaining = time_per_px * (pixel_count - i) as f64;
 
               println!("Adding pixel {i} ({:.1}%), wf = {}, s={}, p={}, r={}, add={}, mr={} mw={}, ETA={:.2}/{:.2}s as {:.2} kpx/s",
-                100.0 * (i as f64) / 4096.0 / 4096.0,
+                100.0 * (i as f64) / (pixel_count as f64),
                 self.root.len(),
                 search_time_src / 1000,
                 place_time_src / 1000,
@@ -269,31 +684,6 @@ impl ColorGenerator {
                 (remaining + time_so_far as f64) / 1000.0 / 1000.0,
                 1000.0 / time_per_px,
               );
-
-              // A horrible idea
-              let mut scanned_leaves = 0;
-              let mut min_leaf_entries = 0;
-              let mut max_leaf_entries = 0;
-              let mut total_leaf_entries = 0;
-
-              // Statistics on how saturated the leaves are
-              // let mut q = Vec::new();
-              // q.push(self.root.as_ref());
-
-              // while let Some(next) = q.pop() {
-              //   match next {
-              //     OctreeLeafy::Node { children, .. } => q.extend_from_slice(children.iter().map(Box::as_ref).collect::<Vec<_>>().as_slice()),
-              //     OctreeLeafy::Leaf { points, .. } => {
-              //       scanned_leaves += 1;
-              //       let len = points.read().len();
-              //       min_leaf_entries = min_leaf_entries.min(len);
-              //       max_leaf_entries = max_leaf_entries.max(len);
-              //       total_leaf_entries += len;
-              //     }
-              //   }
-              // }
-
-              // println!("  Scanned {} leaves, min={}, max={}, avg={}", scanned_leaves, min_leaf_entries, max_leaf_entries, total_leaf_entries / scanned_leaves);
             }
 
             c
@@ -325,7 +715,7 @@ impl ColorGenerator {
 
         trace!("  Search found {result} for {color}");
 
-        if self.writing_spaces.view_bits::<Msb0>()[result.space().0 as usize] {
+        if self.writing_spaces.test(result.space()) {
           // Already written
           // TODO Need to re-dispatch this somehow
           trace!("    Color {} collided at {} with {} total collisions", color, result.space(), color_collisions.len());
@@ -340,35 +730,41 @@ impl ColorGenerator {
         }
 
         // Mark as writing
-        self.writing_spaces.view_bits_mut::<Msb0>().set(result.space().0 as usize, true);
+        self.writing_spaces.set(result.space(), true);
 
         // Paint it
         let start = Instant::now();
-        self.image.write(&result.space(), &color);
+        self.image.write(result.space(), &color);
         let paint_duration = start.elapsed().as_micros() as usize;
         place_time_src += paint_duration;
 
         // Dispatch the result to a mutation thread
         // TODO should we batch these up?
-        let to_remove = self.space_mapping.remove(&result.space()).expect("Should have a found point in our global mapping");
+        let to_remove = self.space_mapping.remove(result.space()).expect("Should have a found point in our global mapping");
         let removals = to_remove.iter().map(|color| Point::new(*result.space(), *color)).collect();
 
         let mut additions = vec![];
-        result.space().get_neighbors(&mut additions);
+        self.topology.neighbors(result.space(), &self.canvas, &mut additions);
         // Attach to the color we placed
         // XXX is that right?
         // TODO also probably avoid these rematerializations?
         let additions: Vec<_> = additions
           .iter()
-          .filter(|space| !self.writing_spaces.view_bits::<Msb0>()[space.0 as usize])
+          .filter(|space| !self.writing_spaces.test(space))
+          .filter(|space| {
+            // Keep track in our space->color map
+            // NB with repeated colors a neighbor might already be waiting on this one
+            let waiting = self.space_mapping.entry(**space).or_default();
+            let fresh = !waiting.contains(&color);
+            if fresh {
+              waiting.push(color);
+            }
+
+            fresh
+          })
           .map(|space| Point::new(*space, color))
           .collect();
 
-        // Keep track in our space->color map
-        for addition in &additions {
-          self.space_mapping.entry(*addition.space()).or_insert(Vec::new()).push(*addition.color());
-        }
-
         for r in &removals { trace!("    Removing {r} because we found {result}"); }
         for a in &additions { trace!("    Adding {a} because it is next to {result}"); }
 
@@ -391,87 +787,600 @@ impl ColorGenerator {
     drop(tx_search_send);
     drop(tx_mutation_send);
 
-    // Wait for everybody to finish
+    // Wait for everybody to finish, and collect any colors we dispatched but never placed
+    let mut unplaced = color_collisions;
     for handle in search_handles {
-      handle.join().unwrap();
+      unplaced.extend(handle.join().unwrap());
     }
+    unplaced.extend(rx_search_receive.try_iter().map(|(color, _, _)| color));
 
     for handle in mutation_handles {
       handle.join().unwrap();
     }
+
+    self.return_colors(unplaced);
+  }
+
+  /// Grows until we run out of places to grow into (or colors)
+  pub fn grow_all(&mut self) {
+    self.grow_pixels_to(self.canvas.size().min(self.colors.len()));
+  }
+
+  /// How many colors we've used up so far
+  pub fn pixels_placed(&self) -> usize {
+    self.current_color_idx
   }
 
+  /// Moves colors we took but didn't end up placing back into the unused part of the palette
+  fn return_colors(&mut self, unplaced: Vec<ColorPoint>) {
+    if unplaced.is_empty() {
+      return;
+    }
+
+    let mut pending: FnvHashMap<ColorPoint, usize> = FnvHashMap::default();
+    for color in &unplaced {
+      *pending.entry(*color).or_default() += 1;
+    }
+
+    // These were all dispatched recently, so walk back from the end of the used colors
+    let mut remaining = unplaced.len();
+    let mut end = self.current_color_idx;
+    let mut idx = end;
+    while remaining > 0 {
+      idx -= 1;
+
+      if let Some(count) = pending.get_mut(&self.colors[idx]).filter(|count| **count > 0) {
+        *count -= 1;
+        remaining -= 1;
+        end -= 1;
+        self.colors.swap(idx, end);
+      }
+    }
 
+    self.current_color_idx = end;
+    self.color_positions = None;
+  }
+
+
+  /// Writes out the canvas, or just the part we painted if we're sparse
   pub fn write_image(&self, path_spec: &String) {
-    let path = Path::new(path_spec);
-    let file = File::create(path).unwrap();
-    let w = BufWriter::new(file);
-
-    let mut encoder = png::Encoder::new(w, 4096, 4096);
-    encoder.set_color(png::ColorType::Rgba);
-    encoder.set_depth(png::BitDepth::Eight);
-    encoder.set_trns(vec!(0xFFu8, 0xFFu8, 0xFFu8, 0xFFu8));
-    encoder.set_source_gamma(png::ScaledFloat::from_scaled(45455)); // 1.0 / 2.2, scaled by 100000
-    encoder.set_source_gamma(png::ScaledFloat::new(1.0 / 2.2));     // 1.0 / 2.2, unscaled, but rounded
-    let source_chromaticities = png::SourceChromaticities::new(     // Using unscaled instantiation here
-        (0.31270, 0.32900),
-        (0.64000, 0.33000),
-        (0.30000, 0.60000),
-        (0.15000, 0.06000)
-    );
-    encoder.set_source_chromaticities(source_chromaticities);
-    let mut writer = encoder.write_header().unwrap();
+    let (canvas, raw) = self.raw_to_write(false);
+    write_png(path_spec, canvas.width, canvas.height, &raw);
+  }
+
+  /// Writes out a canvas grown with a HexTopology, with each cell as a brick of pixels
+  pub fn write_hex_image(&self, path_spec: &String, scale: u32) {
+    let (canvas, raw) = self.raw_to_write(true);
+    let (width, height, raw) = HexTopology.rasterize(&canvas, &raw, scale);
+    write_png(path_spec, width, height, &raw);
+  }
+
+  /// The canvas we'll write out and its pixels, which for a sparse image is cropped down to what we painted
+  /// With `keep_row_parity` the crop starts on an even row, so hex rows stay offset the right way
+  fn raw_to_write(&self, keep_row_parity: bool) -> (Canvas, Vec<u8>) {
+    if !self.image.is_sparse() {
+      return (self.canvas, self.image.to_raw());
+    }
 
+    let (x, mut y, width, mut height) = self.image.written_bounds().expect("Tried to write a sparse image with nothing painted");
+    if keep_row_parity && y % 2 == 1 {
+      y -= 1;
+      height += 1;
+    }
+
+    (Canvas::new(width, height), self.image.to_raw_region(x, y, width, height))
+  }
+
+  /// Writes out each z slice of a volume grown with a VoxelTopology as its own image in `dir`
+  pub fn write_voxel_slices(&self, volume: &Volume, dir: &Path) {
+    assert_eq!(volume.canvas(), self.canvas, "Tried to write {volume:?} from {}", self.canvas);
+
+    // Slices are just runs of rows
     let raw = self.image.to_raw();
-    writer.write_image_data(raw.as_ref()).unwrap(); // Save
+    let slice_len = volume.width as usize * volume.height as usize * 4;
+    let digits = volume.depth.to_string().len();
+
+    for (z, slice) in raw.chunks(slice_len).enumerate() {
+      let path = dir.join(format!("slice_{z:0digits$}.png"));
+      write_png(&path.to_string_lossy().into_owned(), volume.width, volume.height, slice);
+    }
+  }
+
+  /// Writes out a volume grown with a VoxelTopology as raw RGB bytes, x fastest then y then z
+  pub fn write_voxel_raw(&self, volume: &Volume, path: &Path) {
+    assert_eq!(volume.canvas(), self.canvas, "Tried to write {volume:?} from {}", self.canvas);
+
+    let rgb = self.image.to_raw()
+      .chunks(4)
+      .flat_map(|px| [px[0], px[1], px[2]])
+      .collect::<Vec<_>>();
+
+    let mut w = BufWriter::new(File::create(path).unwrap());
+    w.write_all(&rgb).unwrap();
   }
 }
 
-/// Sets up our list of colors and color pointers
-fn initialize_color_space() -> Vec<ColorPoint> {
-  let mut colors = Vec::with_capacity(4096 * 4096);
-  
-  for r in 0..=255u8 {
-    for g in 0..=255u8 {
-      for b in 0..=255u8 {
-        //let idx = usize::from(r) << 16 | usize::from(g) << 8 | usize::from(b);
-        //self.color_space[idx] = &self.colors[idx];
-
-        colors.push(ColorPoint::new(r, g, b));
-      }
+fn write_png(path_spec: &String, width: u32, height: u32, raw: &[u8]) {
+  let path = Path::new(path_spec);
+  let file = File::create(path).unwrap();
+  let w = BufWriter::new(file);
+
+  let mut encoder = png::Encoder::new(w, width, height);
+  encoder.set_color(png::ColorType::Rgba);
+  encoder.set_depth(png::BitDepth::Eight);
+  encoder.set_source_gamma(png::ScaledFloat::from_scaled(45455)); // 1.0 / 2.2, scaled by 100000
+  encoder.set_source_gamma(png::ScaledFloat::new(1.0 / 2.2));     // 1.0 / 2.2, unscaled, but rounded
+  let source_chromaticities = png::SourceChromaticities::new(     // Using unscaled instantiation here
+      (0.31270, 0.32900),
+      (0.64000, 0.33000),
+      (0.30000, 0.60000),
+      (0.15000, 0.06000)
+  );
+  encoder.set_source_chromaticities(source_chromaticities);
+  let mut writer = encoder.write_header().unwrap();
+
+  writer.write_image_data(raw).unwrap(); // Save
+}
+
+/// Checks that a grown canvas is painted with exactly the given palette, each color as often as it's listed
+#[cfg(test)]
+fn assert_painted_with(generator: &ColorGenerator, mut palette: Vec<ColorPoint>) {
+  let mut painted = generator.image.to_raw().chunks(4).map(|px| ColorPoint::new(px[0], px[1], px[2])).collect::<Vec<_>>();
+  painted.sort();
+  palette.sort();
+
+  assert_eq!(painted, palette, "Should have painted every palette color as often as the palette has it");
+}
+
+#[test]
+fn test_small_canvas_uses_whole_palette() {
+  let mut generator = ColorGenerator::new(32, 16);
+  assert_eq!(generator.palette_len(), 32 * 16);
+  generator.set_seed(0);
+
+  generator.shuffle_colors();
+  generator.add_next_seed_pixel(16, 8, &mut Vec::new());
+  generator.grow_pixels_to(32 * 16);
+
+  // Every palette color should show up exactly once
+  assert_painted_with(&generator, palette_of_size(32 * 16));
+}
+
+#[test]
+fn test_seed_reproduces_order() {
+  let mut a = ColorGenerator::new(16, 16);
+  let mut b = ColorGenerator::new(16, 16);
+
+  a.set_seed(1234);
+  b.set_seed(1234);
+  a.shuffle_colors();
+  b.shuffle_colors();
+
+  assert_eq!(a.seed(), 1234);
+  assert_eq!(a.colors, b.colors);
+
+  let mut c = ColorGenerator::new(16, 16);
+  c.set_seed(4321);
+  c.shuffle_colors();
+  assert_ne!(a.colors, c.colors);
+}
+
+#[test]
+fn test_seed_with_explicit_colors() {
+  let mut generator = ColorGenerator::new(16, 16);
+  generator.set_seed(1);
+  generator.shuffle_colors();
+  let mut pool = Vec::new();
+
+  let red = ColorPoint::new(255, 0, 0);
+  let blue = ColorPoint::new(0, 0, 255);
+  assert!(generator.colors.contains(&red) && generator.colors.contains(&blue));
+
+  assert_eq!(generator.add_seed_pixel(0, 0, red, &mut pool), Ok(()));
+  assert_eq!(generator.add_seed_pixel(15, 15, blue, &mut pool), Ok(()));
+  generator.add_next_seed_pixel(8, 8, &mut pool);
+
+  assert_eq!(generator.add_seed_pixel(1, 1, red, &mut pool), Err(SeedError::ColorUsed(red)));
+  assert_eq!(generator.add_seed_pixel(0, 0, ColorPoint::new(0, 0, 0), &mut pool), Err(SeedError::PixelWritten { x: 0, y: 0 }));
+  assert_eq!(generator.add_seed_pixel(16, 0, ColorPoint::new(0, 0, 0), &mut pool), Err(SeedError::OutOfBounds { x: 16, y: 0 }));
+  assert_eq!(generator.add_seed_pixel(1, 1, ColorPoint::new(1, 2, 3), &mut pool), Err(SeedError::NotInPalette(ColorPoint::new(1, 2, 3))));
+
+  generator.grow_pixels_to(16 * 16);
+
+  // The seeds should be where we put them, and still only used once
+  let raw = generator.image.to_raw();
+  assert_eq!(&raw[0..3], &[255, 0, 0]);
+  assert_eq!(&raw[(16 * 16 - 1) * 4..][..3], &[0, 0, 255]);
+
+  assert_painted_with(&generator, palette_of_size(16 * 16));
+}
+
+#[test]
+fn test_seed_layout_skips_written() {
+  let mut generator = ColorGenerator::new(16, 16);
+  let mut pool = Vec::new();
+
+  generator.add_next_seed_pixel(0, 0, &mut pool);
+  assert_eq!(generator.add_seed_layout(&SeedLayout::Border, &mut pool), 59);
+  assert_eq!(generator.add_seed_layout(&SeedLayout::Points(vec![(0, 1), (8, 8), (8, 8)]), &mut pool), 1);
+  assert_eq!(generator.current_color_idx, 61);
+
+  generator.grow_pixels_to(16 * 16);
+}
+
+#[test]
+fn test_grow_from_seed_layout() {
+  // Like main, but starting from a 2x2 grid of seeds rather than just the middle
+  let mut generator = ColorGenerator::new(32, 32);
+  generator.shuffle_colors();
+
+  assert_eq!(generator.add_seed_layout(&SeedLayout::Grid { columns: 2, rows: 2 }, &mut Vec::with_capacity(4)), 4);
+  generator.grow_pixels_to(32 * 32);
+  assert_eq!(generator.pixels_placed(), 32 * 32);
+}
+
+#[test]
+fn test_seed_map() {
+  use crate::seed_map::write_test_png;
+
+  let mut pixels = vec![[0, 0, 0, 0]; 16 * 16];
+  pixels[0] = [255, 0, 0, 255];
+  pixels[16 * 16 - 1] = [0, 0, 255, 255];
+  let path = write_test_png("generator_seed_map", 16, 16, &pixels);
+  let map = SeedMap::read(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+
+  // Has to be the right size
+  let mut generator = ColorGenerator::new(16, 8);
+  assert!(matches!(generator.add_seed_map(&map, false, &mut Vec::new()), Err(SeedMapError::SizeMismatch { .. })));
+
+  let mut generator = ColorGenerator::new(16, 16);
+  assert_eq!(generator.add_seed_map(&map, false, &mut Vec::new()).unwrap(), 2);
+  generator.grow_pixels_to(16 * 16);
+
+  let raw = generator.image.to_raw();
+  assert_eq!(&raw[0..3], &[255, 0, 0]);
+  assert_eq!(&raw[(16 * 16 - 1) * 4..][..3], &[0, 0, 255]);
+
+  // Colors can only be used once
+  pixels[1] = [255, 0, 0, 255];
+  let path = write_test_png("generator_seed_map_dupe", 16, 16, &pixels);
+  let map = SeedMap::read(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+
+  let mut generator = ColorGenerator::new(16, 16);
+  let result = generator.add_seed_map(&map, false, &mut Vec::new());
+  assert!(matches!(result, Err(SeedMapError::Seed(SeedError::ColorUsed(_)))), "Got {result:?}");
+}
+
+#[test]
+fn test_seed_map_snaps_to_palette() {
+  use crate::seed_map::write_test_png;
+
+  // A small canvas only has a lattice of the color cube, which these painted colors are off of
+  let mut pixels = vec![[0, 0, 0, 0]; 16 * 16];
+  pixels[0] = [250, 3, 7, 255];
+  pixels[1] = [250, 3, 7, 255];
+  pixels[16 * 16 - 1] = [10, 20, 240, 255];
+  let path = write_test_png("generator_seed_map_snap", 16, 16, &pixels);
+  let map = SeedMap::read(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+
+  let mut generator = ColorGenerator::new(16, 16);
+  let result = generator.add_seed_map(&map, false, &mut Vec::new());
+  assert!(matches!(result, Err(SeedMapError::Seed(SeedError::NotInPalette(_)))), "Got {result:?}");
+
+  let mut generator = ColorGenerator::new(16, 16);
+  let palette = generator.colors.clone();
+  assert_eq!(generator.add_seed_map(&map, true, &mut Vec::new()).unwrap(), 3);
+  generator.grow_all();
+
+  // Repeats snap to the next nearest color, since each can only be used once
+  let raw = generator.image.to_raw();
+  let painted = |offset: usize| ColorPoint::new(raw[offset * 4], raw[offset * 4 + 1], raw[offset * 4 + 2]);
+  assert_eq!(painted(0), ColorPoint::new(255, 0, 0));
+  assert_ne!(painted(1), painted(0));
+  assert_eq!(painted(16 * 16 - 1), ColorPoint::new(0, 0, 255));
+
+  // Snapping should still use every palette color exactly once
+  assert_painted_with(&generator, palette);
+}
+
+#[test]
+fn test_grow_inside_mask() {
+  let canvas = Canvas::new(32, 32);
+  // Two discs joined by nothing, so only the one we seed should fill
+  let mask = CanvasMask::from_fn(canvas, |x, y| {
+    let disc = |cx: i32, cy: i32| (x as i32 - cx).pow(2) + (y as i32 - cy).pow(2) <= 36;
+    disc(8, 8) || disc(24, 24)
+  });
+  let disc_size = mask.fillable_count() / 2;
+
+  let mut generator = ColorGenerator::new(32, 32);
+  generator.set_seed(2);
+  generator.shuffle_colors();
+  generator.set_mask(mask.clone());
+
+  let mut pool = Vec::new();
+  assert_eq!(generator.add_seed_pixel(0, 0, generator.colors[5], &mut pool), Err(SeedError::Masked { x: 0, y: 0 }));
+  generator.add_next_seed_pixel(8, 8, &mut pool);
+  generator.grow_all();
+
+  assert_eq!(generator.pixels_placed(), disc_size);
+
+  // Only the seeded disc should be painted, and every color we used should be distinct
+  let raw = generator.image.to_raw();
+  let mut painted = Vec::new();
+  for offset in 0..canvas.size() {
+    let (x, y) = canvas.space_at(offset).xy();
+    let px = &raw[offset * 4..][..4];
+    let expect_painted = mask.is_fillable(x, y) && x < 16;
+    assert_eq!(px[3] == 255, expect_painted, "{x},{y} painted wrong");
+
+    if expect_painted {
+      painted.push(ColorPoint::new(px[0], px[1], px[2]));
     }
   }
 
-  colors
+  let mut used = generator.colors[..disc_size].to_vec();
+  used.sort();
+  painted.sort();
+  assert_eq!(painted, used, "The used part of the palette should be exactly what we painted");
 }
 
-/// Sets up our list of points
-fn initialize_space_space() -> SpacePoints {
-  let now = Instant::now();
-  
-  const ZERO: SpacePoint = SpacePoint(0);
-  let mut spaces = Box::new(vec![ZERO; 4096*4096]);
+#[test]
+fn test_grow_around_frozen_pixels() {
+  use crate::seed_map::write_test_png;
 
-  println!("  Alloc spaces in {}", now.elapsed().as_millis());
+  let mut generator = ColorGenerator::new(16, 16);
+  generator.set_seed(3);
+  generator.shuffle_colors();
 
-  for x in 0..4096u32 {
-    for y in 0..4096u32 {
-      let point = &mut spaces[space_offset(x, y)];
-
-      *point = SpacePoint::new(x, y);
+  // Freeze the left half with some of our own palette, plus a color that isn't in it
+  let mut pixels = vec![[0, 0, 0, 0]; 16 * 16];
+  for y in 0..16 {
+    for x in 0..8 {
+      let color = generator.colors[255 - (y * 8 + x)];
+      pixels[y * 16 + x] = [color.r, color.g, color.b, 255];
     }
   }
+  pixels[0] = [1, 2, 3, 255];
+  pixels[1] = [1, 2, 3, 255];
+
+  let path = write_test_png("frozen", 16, 16, &pixels);
+  let map = SeedMap::read(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+
+  assert_eq!(generator.add_frozen_pixels(&map, true, &mut Vec::new()).unwrap(), 128);
+  assert_eq!(generator.pixels_placed(), 126, "Only colors in the palette get used up");
+  assert!(matches!(
+    generator.add_frozen_pixels(&map, true, &mut Vec::new()),
+    Err(SeedMapError::Seed(SeedError::PixelWritten { x: 0, y: 0 })),
+  ));
+
+  generator.grow_all();
+
+  // The frozen half is untouched, and the rest didn't reuse any of its colors
+  let raw = generator.image.to_raw();
+  let painted = raw.chunks(4).map(|px| [px[0], px[1], px[2], px[3]]).collect::<Vec<_>>();
+  for y in 0..16 {
+    assert_eq!(painted[y * 16..][..8], pixels[y * 16..][..8]);
+  }
+
+  let mut grown = (0..16).flat_map(|y| painted[y * 16 + 8..][..8].to_vec()).collect::<Vec<_>>();
+  assert!(grown.iter().all(|px| px[3] == 255), "Everything should be filled");
+  grown.sort();
+  grown.dedup();
+  assert_eq!(grown.len(), 128);
+  assert!(grown.iter().all(|px| !pixels.contains(px)));
+}
 
-  println!("  Init spaces in {}", now.elapsed().as_millis());
+#[test]
+fn test_grow_wraps_around() {
+  use crate::topology::Wrap;
+
+  // Only the left and right columns are fillable, so we can only get from one to the other by wrapping
+  let canvas = Canvas::new(16, 16);
+  let mask = CanvasMask::from_fn(canvas, |x, _| x == 0 || x == 15);
+
+  let mut flat = ColorGenerator::new(16, 16);
+  flat.set_mask(mask.clone());
+  flat.add_next_seed_pixel(0, 0, &mut Vec::new());
+  flat.grow_all();
+  assert_eq!(flat.pixels_placed(), 16);
+
+  let mut wrapped = ColorGenerator::new(16, 16);
+  wrapped.set_mask(mask);
+  wrapped.set_topology(GridTopology::new(Wrap::Horizontal));
+  wrapped.add_next_seed_pixel(0, 0, &mut Vec::new());
+  wrapped.grow_all();
+  assert_eq!(wrapped.pixels_placed(), 32);
+}
+
+#[test]
+fn test_grow_with_neighborhoods() {
+  use crate::topology::{Neighborhood, Wrap};
+
+  // A checkerboard mask is only connected diagonally
+  let canvas = Canvas::new(16, 16);
+  let mask = CanvasMask::from_fn(canvas, |x, y| (x + y) % 2 == 0);
+
+  for (neighborhood, expected) in [(Neighborhood::VonNeumann, 1), (Neighborhood::Moore, 128), (Neighborhood::Radius2, 128)] {
+    let mut generator = ColorGenerator::new(16, 16);
+    generator.set_mask(mask.clone());
+    generator.set_topology(GridTopology::with_neighborhood(Wrap::None, neighborhood.clone()));
+    generator.add_next_seed_pixel(0, 0, &mut Vec::new());
+    generator.grow_all();
 
+    assert_eq!(generator.pixels_placed(), expected, "{neighborhood:?} should reach {expected}");
+  }
+}
 
-  spaces//.map(|s| Arc::new(s))
+#[test]
+fn test_grow_on_hex_grid() {
+  let mut generator = ColorGenerator::new(12, 10);
+  generator.set_topology(HexTopology);
+  generator.add_next_seed_pixel(6, 5, &mut Vec::new());
+  generator.grow_all();
+  assert_eq!(generator.pixels_placed(), 12 * 10);
+
+  let path = std::env::temp_dir().join(format!("rust_colors_{}_hex.png", std::process::id()));
+  generator.write_hex_image(&path.to_string_lossy().into_owned(), 2);
+  let (canvas, pixels) = crate::image::read_png(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+
+  assert_eq!(canvas, Canvas::new(2 * 2 * 12 + 2, 2 * 2 * 10));
+  assert_eq!(pixels.iter().filter(|px| px[3] == 255).count(), 12 * 10 * 16);
 }
 
-// Helpers
+#[test]
+fn test_grow_voxels() {
+  use crate::volume::{VoxelNeighborhood, VoxelTopology};
+
+  // Every color of a 4-level lattice in a 4x4x4 cube
+  let volume = Volume::new(4, 4, 4);
+  let canvas = volume.canvas();
+  let mut generator = ColorGenerator::new(canvas.width, canvas.height);
+  generator.set_topology(VoxelTopology::new(volume, VoxelNeighborhood::Full));
+
+  let (x, y) = volume.space(2, 2, 2).xy();
+  generator.add_next_seed_pixel(x, y, &mut Vec::new());
+  generator.grow_all();
+  assert_eq!(generator.pixels_placed(), 64);
 
-/// Converts an x/y coordinate to an index
-/// TODO get in sync with the SpacePoint one...
-fn space_offset(x: u32, y: u32) -> usize {
-  usize::try_from(y << 12 | x).expect("Should not index a point beyond 2^24")
-}
\ No newline at end of file
+  let dir = std::env::temp_dir().join(format!("rust_colors_{}_voxels", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  generator.write_voxel_slices(&volume, &dir);
+  generator.write_voxel_raw(&volume, &dir.join("volume.raw"));
+
+  let raw = std::fs::read(dir.join("volume.raw")).unwrap();
+  assert_eq!(raw.len(), 64 * 3);
+
+  // The last slice should be the last 16 voxels of the raw file
+  let (slice_canvas, slice) = crate::image::read_png(&dir.join("slice_3.png")).unwrap();
+  assert_eq!(slice_canvas, Canvas::new(4, 4));
+  assert_eq!(slice.iter().flat_map(|px| [px[0], px[1], px[2]]).collect::<Vec<_>>(), raw[48 * 3..]);
+
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_grow_sparse() {
+  let mut generator = ColorGenerator::new_sparse();
+  generator.set_seed(18);
+  generator.set_palette(palette_of_size(4096));
+  generator.shuffle_colors();
+
+  let (x, y) = generator.center();
+  generator.add_next_seed_pixel(x, y, &mut Vec::new());
+  generator.grow_all();
+
+  // We only stop when we run out of colors
+  assert_eq!(generator.pixels_placed(), 4096);
+
+  let (bx, by, width, height) = generator.image.written_bounds().unwrap();
+  assert!(bx <= x && by <= y && x < bx + width && y < by + height);
+  assert!(width * height >= 4096 && width < 1024 && height < 1024, "Grew into {width}x{height}");
+
+  let path = std::env::temp_dir().join(format!("rust_colors_{}_sparse.png", std::process::id()));
+  generator.write_image(&path.to_string_lossy().into_owned());
+  let (canvas, pixels) = crate::image::read_png(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+
+  assert_eq!(canvas, Canvas::new(width, height));
+  assert_eq!(pixels.iter().filter(|[_, _, _, a]| *a == 255).count(), 4096);
+}
+
+#[test]
+fn test_grow_sparse_hex() {
+  // Hex images of a sparse canvas get cropped too, rather than rasterizing the whole plane
+  let mut generator = ColorGenerator::new_sparse();
+  generator.set_seed(18);
+  generator.set_palette(palette_of_size(256));
+  generator.set_topology(HexTopology);
+
+  let (x, y) = generator.center();
+  generator.add_next_seed_pixel(x, y + 1, &mut Vec::new());
+  generator.grow_all();
+
+  let path = std::env::temp_dir().join(format!("rust_colors_{}_sparse_hex.png", std::process::id()));
+  generator.write_hex_image(&path.to_string_lossy().into_owned(), 2);
+  let (canvas, pixels) = crate::image::read_png(&path).unwrap();
+  std::fs::remove_file(&path).unwrap();
+
+  let (_, _, width, _) = generator.image.written_bounds().unwrap();
+  assert_eq!(canvas.width, 2 * 2 * width + 2);
+  assert!(canvas.height < 1024, "Should be cropped, got {canvas}");
+  assert_eq!(pixels.iter().filter(|px| px[3] == 255).count(), 256 * 16);
+}
+
+#[test]
+fn test_grow_with_multiplicity() {
+  let mut generator = ColorGenerator::new(32, 32);
+  generator.set_seed(19);
+  generator.set_multiplicity(4);
+  assert_eq!(generator.palette_len(), 32 * 32);
+  generator.shuffle_colors();
+
+  // We can seed a color as many times as we have copies of it
+  let color = palette_of_size(256)[100];
+  let mut pool = Vec::new();
+  for x in [4, 12, 20, 28] {
+    generator.add_seed_pixel(x, 16, color, &mut pool).unwrap();
+  }
+  assert_eq!(generator.add_seed_pixel(16, 4, color, &mut pool), Err(SeedError::ColorUsed(color)));
+
+  generator.grow_all();
+
+  // Every color should show up exactly 4 times
+  let mut counts: FnvHashMap<ColorPoint, usize> = FnvHashMap::default();
+  for px in generator.image.to_raw().chunks(4) {
+    *counts.entry(ColorPoint::new(px[0], px[1], px[2])).or_default() += 1;
+  }
+
+  assert_eq!(counts.len(), 256);
+  assert!(counts.values().all(|&count| count == 4));
+}
+
+#[test]
+#[ignore = "slow, builds a palette of 16M+ colors"]
+fn test_big_canvas_repeats_colors() {
+  // Just past the color cube, so we need every color twice
+  let generator = ColorGenerator::new(4097, 4096);
+  assert_eq!(generator.palette_len(), 4097 * 4096);
+  assert_eq!(generator.colors[0], generator.colors[1]);
+}
+
+#[test]
+fn test_grow_with_kd_tree() {
+  let mut generator = ColorGenerator::new(48, 32);
+  generator.set_seed(20);
+  generator.set_search_backend(SearchBackend::KdTree);
+  generator.shuffle_colors();
+
+  generator.add_next_seed_pixel(24, 16, &mut Vec::new());
+  generator.grow_all();
+
+  assert_painted_with(&generator, palette_of_size(48 * 32));
+}
+
+#[test]
+#[should_panic(expected = "Tried to switch to the KdTree backend while breaking ties by Random")]
+fn test_random_ties_need_octree() {
+  let mut generator = ColorGenerator::new(16, 16);
+  generator.set_tie_break(TieBreak::Random { seed: 25 });
+  generator.set_search_backend(SearchBackend::KdTree);
+}
+
+#[test]
+fn test_grow_with_random_ties() {
+  let mut generator = ColorGenerator::new(48, 32);
+  generator.set_seed(20);
+  generator.set_tie_break(TieBreak::Random { seed: 25 });
+  generator.shuffle_colors();
+
+  generator.add_next_seed_pixel(24, 16, &mut Vec::new());
+  generator.grow_all();
+
+  assert_painted_with(&generator, palette_of_size(48 * 32));
+}
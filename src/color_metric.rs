@@ -0,0 +1,107 @@
+use integer_sqrt::IntegerSquareRoot;
+
+use crate::points::ColorPoint;
+
+/// How we measure the distance between two colors during nearest neighbor searches
+/// All of these are integral and monotonic, so "nearest" is well defined, but note that
+/// the Euclidean variants are squared distances
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum ColorMetric {
+    /// Squared straight-line distance through the RGB cube
+    #[default]
+    Euclidean,
+    /// Sum of the per-channel differences
+    Manhattan,
+    /// Largest of the per-channel differences
+    Chebyshev,
+    /// Squared straight-line distance with each channel scaled by a weight
+    /// Weights must be positive, and under ~11000 to keep RGB distances exact inside an i32
+    WeightedEuclidean { r: i32, g: i32, b: i32 },
+}
+
+impl ColorMetric {
+    pub fn weighted(r: i32, g: i32, b: i32) -> ColorMetric {
+        assert!(r > 0 && g > 0 && b > 0, "Tried to make a weighted metric with non-positive weights {r}, {g}, {b}");
+        assert!(r.max(g).max(b) <= 11000, "Tried to make a weighted metric with weights {r}, {g}, {b} that could overflow");
+
+        ColorMetric::WeightedEuclidean { r, g, b }
+    }
+
+    #[inline]
+    pub fn distance(&self, a: &ColorPoint, b: &ColorPoint) -> i32 {
+        self.delta_distance(
+            a.r as i32 - b.r as i32,
+            a.g as i32 - b.g as i32,
+            a.b as i32 - b.b as i32,
+        )
+    }
+
+    /// The distance for the given per-channel differences
+    #[inline]
+    pub fn delta_distance(&self, dr: i32, dg: i32, db: i32) -> i32 {
+        match *self {
+            ColorMetric::Euclidean => dr * dr + dg * dg + db * db,
+            ColorMetric::Manhattan => dr.abs() + dg.abs() + db.abs(),
+            ColorMetric::Chebyshev => dr.abs().max(dg.abs()).max(db.abs()),
+            // Saturate rather than overflow, as perceptual spaces can be a fair bit bigger than RGB
+            ColorMetric::WeightedEuclidean { r, g, b } => r.saturating_mul(dr * dr)
+                .saturating_add(g.saturating_mul(dg * dg))
+                .saturating_add(b.saturating_mul(db * db)),
+        }
+    }
+
+    /// The per-channel half widths of the smallest box that holds every color within `distance`
+    /// This is what lets the bounding box pruning work with any metric
+    pub fn radii(&self, distance: i32) -> (i32, i32, i32) {
+        assert!(distance >= 0, "Tried to get radii for a negative distance {distance}");
+
+        match *self {
+            ColorMetric::Euclidean => {
+                let radius = distance.integer_sqrt();
+                (radius, radius, radius)
+            }
+            ColorMetric::Manhattan | ColorMetric::Chebyshev => (distance, distance, distance),
+            // w * d^2 <= distance, and d^2 is integral, so d^2 <= floor(distance / w)
+            ColorMetric::WeightedEuclidean { r, g, b } => (
+                (distance / r).integer_sqrt(),
+                (distance / g).integer_sqrt(),
+                (distance / b).integer_sqrt(),
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_metric_distances() {
+    let a = ColorPoint::new(10, 20, 30);
+    let b = ColorPoint::new(13, 16, 30);
+
+    assert_eq!(ColorMetric::Euclidean.distance(&a, &b), 9 + 16);
+    assert_eq!(ColorMetric::Euclidean.distance(&a, &b), a.distance_to(&b));
+    assert_eq!(ColorMetric::Manhattan.distance(&a, &b), 3 + 4);
+    assert_eq!(ColorMetric::Chebyshev.distance(&a, &b), 4);
+    assert_eq!(ColorMetric::weighted(2, 3, 4).distance(&a, &b), 2 * 9 + 3 * 16);
+
+    for metric in [ColorMetric::Euclidean, ColorMetric::Manhattan, ColorMetric::Chebyshev, ColorMetric::weighted(2, 3, 4)] {
+        assert_eq!(metric.distance(&a, &a), 0);
+        assert_eq!(metric.distance(&a, &b), metric.distance(&b, &a));
+    }
+}
+
+#[test]
+fn test_metric_radii_contain_ball() {
+    // Everything within the distance along a single axis should be inside the radii
+    let metrics = [ColorMetric::Euclidean, ColorMetric::Manhattan, ColorMetric::Chebyshev, ColorMetric::weighted(1, 5, 30)];
+
+    for metric in metrics {
+        for distance in [0, 1, 2, 29, 30, 31, 100, 1000] {
+            let (rr, rg, rb) = metric.radii(distance);
+
+            for delta in 0..64 {
+                if metric.delta_distance(delta, 0, 0) <= distance { assert!(delta <= rr, "{metric:?} r {delta} > {rr} at {distance}"); }
+                if metric.delta_distance(0, delta, 0) <= distance { assert!(delta <= rg, "{metric:?} g {delta} > {rg} at {distance}"); }
+                if metric.delta_distance(0, 0, delta) <= distance { assert!(delta <= rb, "{metric:?} b {delta} > {rb} at {distance}"); }
+            }
+        }
+    }
+}
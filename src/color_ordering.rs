@@ -0,0 +1,328 @@
+use fastrand::Rng;
+
+use crate::{color_space::ColorSpace, points::ColorPoint};
+
+/// Decides what order we place the palette in, which is most of what gives a render its look
+/// Orderings should only rearrange the colors, and get any randomness from the given rng
+pub trait ColorOrdering {
+    fn order(&self, colors: &mut [ColorPoint], rng: &Rng);
+}
+
+/// A full Fisher-Yates shuffle, the classic allRGB look
+pub struct Shuffled;
+
+/// Around the color wheel starting from red, with grays first
+pub struct ByHue;
+
+/// Darkest first, by Rec. 709 luma
+pub struct ByLuminance;
+
+/// Grays first, by HSV saturation
+pub struct BySaturation;
+
+/// Along a 3D Hilbert curve through the RGB cube, so consecutive colors are always close
+pub struct HilbertOrder;
+
+/// Along a Z-order curve through the RGB cube, cheaper than Hilbert but with some big jumps
+pub struct MortonOrder;
+
+/// Some other ordering, then each color jittered up to `window` places later
+/// Small windows keep the overall sweep with some local noise, large ones approach a shuffle
+pub struct NoisySort<O: ColorOrdering> {
+    sort: O,
+    window: usize,
+}
+
+/// Shuffles whole runs of `block_size` colors rather than single colors, like LOOSESHUFFLE in the C++ version
+/// Neighboring palette entries stay together, which gives long coherent streaks
+/// NB the C++ used blocks of 4096, i.e. one row of the full palette
+pub struct BlockShuffle {
+    block_size: usize,
+    shuffle_within: bool,
+}
+
+impl ColorOrdering for Shuffled {
+    fn order(&self, colors: &mut [ColorPoint], rng: &Rng) {
+        rng.shuffle(colors);
+    }
+}
+
+impl ColorOrdering for ByHue {
+    fn order(&self, colors: &mut [ColorPoint], _rng: &Rng) {
+        // NB grays have no hue, so they'd tie with red at 0 if we didn't sort them ahead of everything
+        colors.sort_by_key(|color| {
+            let is_gray = color.r == color.g && color.g == color.b;
+            (!is_gray, ColorSpace::Hsv.coords(color)[0])
+        });
+    }
+}
+
+impl ColorOrdering for ByLuminance {
+    fn order(&self, colors: &mut [ColorPoint], _rng: &Rng) {
+        colors.sort_by_key(|color| 2126 * color.r as u32 + 7152 * color.g as u32 + 722 * color.b as u32);
+    }
+}
+
+impl ColorOrdering for BySaturation {
+    fn order(&self, colors: &mut [ColorPoint], _rng: &Rng) {
+        colors.sort_by_key(|color| ColorSpace::Hsv.coords(color)[1]);
+    }
+}
+
+impl ColorOrdering for HilbertOrder {
+    fn order(&self, colors: &mut [ColorPoint], _rng: &Rng) {
+        colors.sort_by_key(hilbert_index);
+    }
+}
+
+impl ColorOrdering for MortonOrder {
+    fn order(&self, colors: &mut [ColorPoint], _rng: &Rng) {
+        colors.sort_by_key(|color| interleave([color.r as u32, color.g as u32, color.b as u32]));
+    }
+}
+
+impl<O: ColorOrdering> NoisySort<O> {
+    pub fn new(sort: O, window: usize) -> NoisySort<O> {
+        assert!(window > 0, "Tried to make a noisy sort with an empty window");
+
+        NoisySort { sort, window }
+    }
+}
+
+impl<O: ColorOrdering> ColorOrdering for NoisySort<O> {
+    fn order(&self, colors: &mut [ColorPoint], rng: &Rng) {
+        self.sort.order(colors, rng);
+
+        // Push everybody back a random amount and settle it out
+        let mut keyed = colors.iter()
+            .enumerate()
+            .map(|(i, color)| (i + rng.usize(..self.window), *color))
+            .collect::<Vec<_>>();
+        keyed.sort_by_key(|&(key, _)| key);
+
+        for (slot, (_, color)) in colors.iter_mut().zip(keyed) {
+            *slot = color;
+        }
+    }
+}
+
+impl BlockShuffle {
+    /// With `shuffle_within` set we also shuffle the colors inside each block
+    pub fn new(block_size: usize, shuffle_within: bool) -> BlockShuffle {
+        assert!(block_size > 0, "Tried to make a block shuffle with empty blocks");
+
+        BlockShuffle { block_size, shuffle_within }
+    }
+}
+
+impl ColorOrdering for BlockShuffle {
+    fn order(&self, colors: &mut [ColorPoint], rng: &Rng) {
+        // NB any leftover partial block just gets moved around like the rest
+        let mut blocks = colors.chunks(self.block_size)
+            .map(|block| block.to_vec())
+            .collect::<Vec<_>>();
+        rng.shuffle(&mut blocks);
+
+        if self.shuffle_within {
+            // Before flattening, as a partial block throws off where the others end up
+            for block in blocks.iter_mut() {
+                rng.shuffle(block);
+            }
+        }
+
+        for (slot, color) in colors.iter_mut().zip(blocks.into_iter().flatten()) {
+            *slot = color;
+        }
+    }
+}
+
+/// Interleaves the bits of 8-bit coordinates, first axis most significant
+fn interleave(axes: [u32; 3]) -> u32 {
+    let mut index = 0;
+    for bit in (0..8).rev() {
+        for axis in axes {
+            index = index << 1 | (axis >> bit) & 1;
+        }
+    }
+
+    index
+}
+
+/// Position along the Hilbert curve, per John Skilling's "Programming the Hilbert curve" (2004)
+fn hilbert_index(color: &ColorPoint) -> u32 {
+    let mut x = [color.r as u32, color.g as u32, color.b as u32];
+
+    // Undo the excess work of the rotations
+    let mut q = 1 << 7;
+    while q > 1 {
+        let p = q - 1;
+        for i in 0..3 {
+            if x[i] & q != 0 {
+                x[0] ^= p;
+            } else {
+                let t = (x[0] ^ x[i]) & p;
+                x[0] ^= t;
+                x[i] ^= t;
+            }
+        }
+        q >>= 1;
+    }
+
+    // Gray encode
+    x[1] ^= x[0];
+    x[2] ^= x[1];
+
+    let mut t = 0;
+    let mut q = 1 << 7;
+    while q > 1 {
+        if x[2] & q != 0 {
+            t ^= q - 1;
+        }
+        q >>= 1;
+    }
+
+    interleave(x.map(|axis| axis ^ t))
+}
+
+#[test]
+fn test_hilbert_order_is_continuous() {
+    // The Hilbert curve fills sub-cubes one at a time, so this corner comes first in one piece
+    let mut colors = (0..16 * 16 * 16)
+        .map(|i| ColorPoint::new(i as u8 & 15, (i >> 4) as u8 & 15, (i >> 8) as u8))
+        .collect::<Vec<_>>();
+
+    HilbertOrder.order(&mut colors, &Rng::with_seed(0));
+
+    assert_eq!(colors[0], ColorPoint::new(0, 0, 0));
+    for pair in colors.windows(2) {
+        assert_eq!(pair[0].distance_to(&pair[1]), 1, "{:?} should be next to {:?}", pair[0], pair[1]);
+    }
+}
+
+#[test]
+fn test_orderings_are_permutations() {
+    let rng = Rng::with_seed(5);
+    let palette = (0..2000).map(|_| ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..))).collect::<Vec<_>>();
+
+    let orderings: [&dyn ColorOrdering; 9] = [
+        &Shuffled,
+        &ByHue,
+        &ByLuminance,
+        &BySaturation,
+        &HilbertOrder,
+        &MortonOrder,
+        &NoisySort::new(ByLuminance, 50),
+        &BlockShuffle::new(64, false),
+        &BlockShuffle::new(300, true),
+    ];
+
+    let mut expected = palette.clone();
+    expected.sort();
+
+    for ordering in orderings {
+        let mut colors = palette.clone();
+        ordering.order(&mut colors, &rng);
+        colors.sort();
+        assert_eq!(colors, expected);
+    }
+}
+
+#[test]
+fn test_sorted_orderings() {
+    let rng = Rng::with_seed(6);
+    let mut colors = vec![
+        ColorPoint::new(0, 0, 255),
+        ColorPoint::new(255, 255, 255),
+        ColorPoint::new(0, 255, 0),
+        ColorPoint::new(255, 0, 0),
+        ColorPoint::new(0, 0, 0),
+    ];
+
+    ByHue.order(&mut colors, &rng);
+    // Grays have no hue, so come first in their original order
+    assert_eq!(colors, [
+        ColorPoint::new(255, 255, 255),
+        ColorPoint::new(0, 0, 0),
+        ColorPoint::new(255, 0, 0),
+        ColorPoint::new(0, 255, 0),
+        ColorPoint::new(0, 0, 255),
+    ]);
+
+    ByLuminance.order(&mut colors, &rng);
+    assert_eq!(colors, [
+        ColorPoint::new(0, 0, 0),
+        ColorPoint::new(0, 0, 255),
+        ColorPoint::new(255, 0, 0),
+        ColorPoint::new(0, 255, 0),
+        ColorPoint::new(255, 255, 255),
+    ]);
+
+    MortonOrder.order(&mut colors, &rng);
+    assert_eq!(colors[0], ColorPoint::new(0, 0, 0));
+    assert_eq!(colors[4], ColorPoint::new(255, 255, 255));
+}
+
+#[test]
+fn test_noisy_sort_stays_local() {
+    let rng = Rng::with_seed(7);
+    let mut colors = (0..=255).map(|v| ColorPoint::new(v, v, v)).collect::<Vec<_>>();
+
+    NoisySort::new(ByLuminance, 8).order(&mut colors, &rng);
+
+    // Nobody should have moved further than the window
+    for (i, color) in colors.iter().enumerate() {
+        assert!((color.r as i32 - i as i32).abs() < 8, "{color:?} ended up at {i}");
+    }
+    assert!(colors.windows(2).any(|pair| pair[0].r > pair[1].r), "Should have some noise");
+}
+
+#[test]
+fn test_block_shuffle_keeps_blocks_together() {
+    let rng = Rng::with_seed(8);
+    let palette = (0..=255).map(|v| ColorPoint::new(v, 0, 0)).collect::<Vec<_>>();
+
+    let mut colors = palette.clone();
+    BlockShuffle::new(16, false).order(&mut colors, &rng);
+    assert_ne!(colors, palette);
+
+    // Each block should be an intact run from the original
+    for block in colors.chunks(16) {
+        assert_eq!(block[0].r % 16, 0);
+        assert!(block.windows(2).all(|pair| pair[1].r == pair[0].r + 1));
+    }
+
+    // Shuffling within still keeps the same colors in each block
+    BlockShuffle::new(16, true).order(&mut colors, &rng);
+    for block in colors.chunks(16) {
+        let mut block = block.to_vec();
+        block.sort();
+        assert!(block.windows(2).all(|pair| pair[1].r == pair[0].r + 1));
+    }
+}
+
+#[test]
+fn test_block_shuffle_with_partial_block() {
+    // 250 colors in blocks of 16 leaves a block of 10 at the end
+    let rng = Rng::with_seed(9);
+    let palette = (0..250).map(|v| ColorPoint::new(v, 0, 0)).collect::<Vec<_>>();
+
+    let mut colors = palette.clone();
+    BlockShuffle::new(16, true).order(&mut colors, &rng);
+
+    // Every original block should still be a contiguous run of the output, just jumbled
+    let mut at = 0;
+    while at < colors.len() {
+        let start = colors[at].r / 16 * 16;
+        let len = if start == 240 { 10 } else { 16 };
+
+        let mut block = colors[at..at + len].iter().map(|c| c.r).collect::<Vec<_>>();
+        block.sort();
+        assert_eq!(block, (start..start + len as u8).collect::<Vec<_>>(), "Block at {at} got mixed with another");
+
+        at += len;
+    }
+
+    let mut sorted = colors;
+    sorted.sort();
+    assert_eq!(sorted, palette);
+}
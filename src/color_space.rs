@@ -0,0 +1,326 @@
+use std::thread;
+
+use once_cell::sync::Lazy;
+
+use crate::{bounding_box::BoundingBox, color_metric::ColorMetric, palette::FULL_PALETTE_SIZE, points::ColorPoint};
+
+/// A color's position in some color space, scaled and rounded to integers
+pub type Coords = [i32; 3];
+
+/// How many integer steps we give to a lightness of 0..1 in the perceptual spaces
+/// About one step per just noticeable difference
+const PERCEPTUAL_SCALE: f64 = 500.0;
+
+/// How many integer steps the hue axis takes to go once around the color wheel, two per degree
+pub const HUE_PERIOD: i32 = 720;
+
+/// Which space we run our nearest neighbor searches in
+/// The image is always sRGB, this only changes what "near" means
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum ColorSpace {
+    /// Plain sRGB channel values, 0..=255 on each axis
+    #[default]
+    Rgb,
+    /// Björn Ottosson's OKLab, as L, a, b
+    OkLab,
+    /// CIE L*a*b* with a D65 white point, as L, a, b
+    CieLab,
+    /// Hue, saturation, value, where hue wraps around every HUE_PERIOD steps
+    /// Saturation and value are 0..=255, and grays all get a hue of 0
+    Hsv,
+    /// Hue, saturation, lightness, with the same hue axis as Hsv
+    Hsl,
+}
+
+/// Every 24-bit color converted ahead of time, plus the range they cover
+struct SpaceTable {
+    coords: Vec<[i16; 3]>,
+    lower: Coords,
+    upper: Coords,
+}
+
+static OKLAB_TABLE: Lazy<SpaceTable> = Lazy::new(|| SpaceTable::build(ColorSpace::OkLab));
+static CIELAB_TABLE: Lazy<SpaceTable> = Lazy::new(|| SpaceTable::build(ColorSpace::CieLab));
+
+impl ColorSpace {
+    /// Where the color lands in this space
+    /// Perceptual spaces use the precomputed tables, so the first call in each is slow
+    #[inline]
+    pub fn coords(&self, color: &ColorPoint) -> Coords {
+        match self {
+            ColorSpace::Rgb => [color.r as i32, color.g as i32, color.b as i32],
+            ColorSpace::OkLab => OKLAB_TABLE.get(color),
+            ColorSpace::CieLab => CIELAB_TABLE.get(color),
+            // These are cheap enough to not bother with a table
+            ColorSpace::Hsv | ColorSpace::Hsl => self.convert(color),
+        }
+    }
+
+    /// If the first axis is periodic (i.e. hue), how far along it we go before wrapping back around
+    #[inline]
+    pub fn wrap_period(&self) -> Option<i32> {
+        match self {
+            ColorSpace::Hsv | ColorSpace::Hsl => Some(HUE_PERIOD),
+            _ => None,
+        }
+    }
+
+    /// The per-axis differences between two coords, going the short way around any periodic axis
+    #[inline]
+    pub fn delta(&self, a: &Coords, b: &Coords) -> (i32, i32, i32) {
+        let d0 = match self.wrap_period() {
+            None => a[0] - b[0],
+            Some(period) => {
+                let d0 = (a[0] - b[0]).rem_euclid(period);
+                if d0 > period / 2 { d0 - period } else { d0 }
+            }
+        };
+
+        (d0, a[1] - b[1], a[2] - b[2])
+    }
+
+    /// The distance between two colors' coords in this space, as measured by the metric
+    #[inline]
+    pub fn distance(&self, metric: &ColorMetric, a: &Coords, b: &Coords) -> i32 {
+        let (d0, d1, d2) = self.delta(a, b);
+        metric.delta_distance(d0, d1, d2)
+    }
+
+    /// The range every color's coords falls in (inclusive), which need not be a cube
+    pub fn bounds(&self) -> BoundingBox {
+        let ([lr, lg, lb], [ur, ug, ub]) = match self {
+            ColorSpace::Rgb => ([0, 0, 0], [255, 255, 255]),
+            ColorSpace::OkLab => (OKLAB_TABLE.lower, OKLAB_TABLE.upper),
+            ColorSpace::CieLab => (CIELAB_TABLE.lower, CIELAB_TABLE.upper),
+            ColorSpace::Hsv | ColorSpace::Hsl => ([0, 0, 0], [HUE_PERIOD - 1, 255, 255]),
+        };
+
+        BoundingBox::new(lr, lg, lb, ur, ug, ub)
+    }
+
+    /// Does the actual conversion without going through our tables
+    pub fn convert(&self, color: &ColorPoint) -> Coords {
+        match self {
+            ColorSpace::Rgb => self.coords(color),
+            ColorSpace::OkLab => {
+                let (r, g, b) = linear_rgb(color);
+
+                let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+                let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+                let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+
+                scale([
+                    0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+                    1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+                    0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+                ])
+            }
+            ColorSpace::CieLab => {
+                let (r, g, b) = linear_rgb(color);
+
+                // To XYZ, relative to the D65 white point
+                let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+                let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+                let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+                let (fx, fy, fz) = (lab_f(x), lab_f(y), lab_f(z));
+
+                // L is 0..100 here, so bring it down to 0..1 like OKLab before scaling
+                scale([
+                    (116.0 * fy - 16.0) / 100.0,
+                    5.0 * (fx - fy),
+                    2.0 * (fy - fz),
+                ])
+            }
+            ColorSpace::Hsv => {
+                let (max, min) = channel_range(color);
+                let saturation = if max == 0 { 0 } else { rounded_div((max - min) * 255, max) };
+
+                [hue(color), saturation, max]
+            }
+            ColorSpace::Hsl => {
+                let (max, min) = channel_range(color);
+                // NB the chroma is relative to how far we can get from gray at this lightness
+                let reach = 255 - (max + min - 255).abs();
+                let saturation = if max == min { 0 } else { rounded_div((max - min) * 255, reach) };
+
+                [hue(color), saturation, rounded_div(max + min, 2)]
+            }
+        }
+    }
+}
+
+impl SpaceTable {
+    fn build(space: ColorSpace) -> SpaceTable {
+        let mut coords = vec![[0i16; 3]; FULL_PALETTE_SIZE];
+
+        // This is a few seconds of work single threaded, so split it up by blue
+        // NB indexed the same as ColorPoint::offset
+        let chunk_size = FULL_PALETTE_SIZE / 16;
+        thread::scope(|scope| {
+            for (chunk_idx, chunk) in coords.chunks_mut(chunk_size).enumerate() {
+                scope.spawn(move || {
+                    for (i, entry) in chunk.iter_mut().enumerate() {
+                        let offset = chunk_idx * chunk_size + i;
+                        let color = ColorPoint::new(offset as u8, (offset >> 8) as u8, (offset >> 16) as u8);
+                        let [c0, c1, c2] = space.convert(&color);
+                        *entry = [c0 as i16, c1 as i16, c2 as i16];
+                    }
+                });
+            }
+        });
+
+        let mut lower = [i32::MAX; 3];
+        let mut upper = [i32::MIN; 3];
+        for entry in &coords {
+            for axis in 0..3 {
+                lower[axis] = lower[axis].min(entry[axis] as i32);
+                upper[axis] = upper[axis].max(entry[axis] as i32);
+            }
+        }
+
+        SpaceTable { coords, lower, upper }
+    }
+
+    #[inline]
+    fn get(&self, color: &ColorPoint) -> Coords {
+        let [c0, c1, c2] = self.coords[color.offset()];
+        [c0 as i32, c1 as i32, c2 as i32]
+    }
+}
+
+/// The sRGB transfer function undone for each channel value, 0..1
+static LINEAR_RGB: Lazy<[f64; 256]> = Lazy::new(|| {
+    std::array::from_fn(|c| {
+        let c = c as f64 / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    })
+});
+
+fn linear_rgb(color: &ColorPoint) -> (f64, f64, f64) {
+    (LINEAR_RGB[color.r as usize], LINEAR_RGB[color.g as usize], LINEAR_RGB[color.b as usize])
+}
+
+fn lab_f(t: f64) -> f64 {
+    let delta: f64 = 6.0 / 29.0;
+    if t > delta * delta * delta { t.cbrt() } else { t / (3.0 * delta * delta) + 4.0 / 29.0 }
+}
+
+fn channel_range(color: &ColorPoint) -> (i32, i32) {
+    let (r, g, b) = (color.r as i32, color.g as i32, color.b as i32);
+    (r.max(g).max(b), r.min(g).min(b))
+}
+
+/// Where the color sits on the color wheel, 0..HUE_PERIOD with red at 0
+fn hue(color: &ColorPoint) -> i32 {
+    let (r, g, b) = (color.r as i32, color.g as i32, color.b as i32);
+    let (max, min) = channel_range(color);
+    let chroma = max - min;
+
+    if chroma == 0 {
+        return 0;
+    }
+
+    // Each sixth of the wheel is a linear ramp between two of the primaries and secondaries
+    let sixth = HUE_PERIOD / 6;
+    let (start, along) = if max == r {
+        (0, g - b)
+    } else if max == g {
+        (2 * sixth, b - r)
+    } else {
+        (4 * sixth, r - g)
+    };
+
+    (start + rounded_div(along * sixth, chroma)).rem_euclid(HUE_PERIOD)
+}
+
+/// Integer division rounding half up, which also behaves for negative numerators
+fn rounded_div(numerator: i32, denominator: i32) -> i32 {
+    (2 * numerator + denominator).div_euclid(2 * denominator)
+}
+
+fn scale(coords: [f64; 3]) -> Coords {
+    coords.map(|c| (c * PERCEPTUAL_SCALE).round() as i32)
+}
+
+#[test]
+fn test_perceptual_conversions() {
+    // Black and white should sit at the ends of the lightness axis with no chroma
+    for space in [ColorSpace::OkLab, ColorSpace::CieLab] {
+        let black = space.convert(&ColorPoint::new(0, 0, 0));
+        let white = space.convert(&ColorPoint::new(255, 255, 255));
+
+        assert_eq!(black, [0, 0, 0], "{space:?} black");
+        assert_eq!(white[0], PERCEPTUAL_SCALE as i32, "{space:?} white lightness");
+        assert!(white[1].abs() <= 1 && white[2].abs() <= 1, "{space:?} white should be neutral, got {white:?}");
+    }
+
+    // Some known values, OKLab red is (0.628, 0.225, 0.126)
+    assert_eq!(ColorSpace::OkLab.convert(&ColorPoint::new(255, 0, 0)), [314, 112, 63]);
+    // CIELAB red is (53.24, 80.09, 67.20)
+    assert_eq!(ColorSpace::CieLab.convert(&ColorPoint::new(255, 0, 0)), [266, 400, 336]);
+}
+
+#[test]
+fn test_cylindrical_conversions() {
+    let hsv = ColorSpace::Hsv;
+
+    // The primaries and secondaries are evenly spaced around the wheel
+    assert_eq!(hsv.coords(&ColorPoint::new(255, 0, 0)), [0, 255, 255]);
+    assert_eq!(hsv.coords(&ColorPoint::new(255, 255, 0)), [120, 255, 255]);
+    assert_eq!(hsv.coords(&ColorPoint::new(0, 255, 0)), [240, 255, 255]);
+    assert_eq!(hsv.coords(&ColorPoint::new(0, 0, 255)), [480, 255, 255]);
+    assert_eq!(hsv.coords(&ColorPoint::new(255, 0, 255)), [600, 255, 255]);
+    assert_eq!(hsv.coords(&ColorPoint::new(128, 128, 128)), [0, 0, 128]);
+
+    assert_eq!(ColorSpace::Hsl.coords(&ColorPoint::new(255, 0, 0)), [0, 255, 128]);
+    assert_eq!(ColorSpace::Hsl.coords(&ColorPoint::new(255, 255, 255)), [0, 0, 255]);
+
+    // Reds either side of 0 should be close, going the short way around
+    let a = hsv.coords(&ColorPoint::new(255, 0, 40));
+    let b = hsv.coords(&ColorPoint::new(255, 40, 0));
+    assert_eq!(a, [701, 255, 255]);
+    assert_eq!(b, [19, 255, 255]);
+    assert_eq!(hsv.delta(&a, &b), (-38, 0, 0));
+    assert_eq!(hsv.delta(&b, &a), (38, 0, 0));
+    assert_eq!(hsv.distance(&ColorMetric::Manhattan, &a, &b), 38);
+
+    // And everything should stay in bounds
+    let bounds = hsv.bounds();
+    let rng = fastrand::Rng::with_seed(77);
+    for space in [ColorSpace::Hsv, ColorSpace::Hsl] {
+        for _ in 0..1000 {
+            let coords = space.coords(&ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..)));
+            assert!(bounds.contains_coords(&coords), "{space:?} {coords:?} should be inside {bounds}");
+        }
+    }
+}
+
+#[test]
+fn test_rgb_space_is_identity() {
+    let color = ColorPoint::new(12, 34, 56);
+    assert_eq!(ColorSpace::Rgb.coords(&color), [12, 34, 56]);
+    assert_eq!(ColorSpace::Rgb.bounds(), BoundingBox::new(0, 0, 0, 255, 255, 255));
+}
+
+#[test]
+fn test_perceptual_tables() {
+    let rng = fastrand::Rng::with_seed(99);
+
+    for space in [ColorSpace::OkLab, ColorSpace::CieLab] {
+        let bounds = space.bounds();
+
+        for _ in 0..1000 {
+            let color = ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..));
+            let coords = space.coords(&color);
+
+            assert_eq!(coords, space.convert(&color), "{space:?} table disagrees for {color:?}");
+            assert!(bounds.contains_coords(&coords), "{space:?} {coords:?} should be inside {bounds}");
+        }
+
+        // Lightness runs from black to white, but the other axes are not cubic
+        assert_eq!(bounds.lr, 0);
+        assert_eq!(bounds.ur, PERCEPTUAL_SCALE as i32);
+        assert!(bounds.lg < 0 && bounds.lb < 0);
+    }
+}
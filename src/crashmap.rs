@@ -1,4 +1,4 @@
-use std::{sync::{RwLock, atomic::{Ordering, AtomicIsize}}, hash::Hasher, collections::BTreeMap};
+use std::{sync::{RwLock, atomic::{Ordering, AtomicIsize}}, hash::Hasher, collections::{BTreeMap, btree_map::Entry}};
 use fnv::FnvHasher;
 
 use crate::atomicbitmask::AtomicBitMask;
@@ -56,11 +56,11 @@ impl<K: core::hash::Hash + Ord + Clone + Copy, V> CrashMap<K, V> {
         key_hash as usize & (self.get_capacity() - 1)
     }
 
-    fn get_bin<'a>(&'a self, key: K) -> &'a BinType<K, V> {
+    fn get_bin(&self, key: K) -> &BinType<K, V> {
         self.get_bin_idx(key).0
     }
 
-    fn get_bin_idx<'a>(&'a self, key: K) -> (&'a BinType<K, V>, usize) {
+    fn get_bin_idx(&self, key: K) -> (&BinType<K, V>, usize) {
         let bin_idx = self.bin_idx(key);
         (&self.bins[bin_idx], bin_idx)
     }
@@ -76,14 +76,11 @@ impl<K: core::hash::Hash + Ord + Clone + Copy, V> CrashMap<K, V> {
         
         let ret = writer.insert(key, value);
 
-        if writer.len() > 0 {
+        if !writer.is_empty() {
             self.occupation.test_and_set(idx);
         }
 
-        match ret {
-            None => { self.count.fetch_add(1, Ordering::Relaxed); },
-            _ => {}
-        };
+        if ret.is_none() { self.count.fetch_add(1, Ordering::Relaxed); };
 
         ret
     }
@@ -94,19 +91,36 @@ impl<K: core::hash::Hash + Ord + Clone + Copy, V> CrashMap<K, V> {
         
         let ret = writer.remove(&key);
 
-        if writer.len() == 0 {
+        if writer.is_empty() {
             self.occupation.clear(idx);
         }
 
-        match ret {
-            Some(_) => { self.count.fetch_add(-1, Ordering::Relaxed); },
-            _ => {}
-        };
+        if ret.is_some() { self.count.fetch_add(-1, Ordering::Relaxed); };
 
         ret
     }
 
-    pub fn foreach_lockfree<F: FnMut((&K, &V)) -> ()>(&self, mut f: F) -> () {
+    /// Removes the entry only if it still passes the check, all under the same lock
+    pub fn remove_if<F: FnOnce(&V) -> bool>(&self, key: K, f: F) -> Option<V> {
+        let (bin, idx) = self.get_bin_idx(key);
+        let mut writer = bin.write().unwrap();
+
+        if !writer.get(&key).is_some_and(f) {
+            return None;
+        }
+
+        let ret = writer.remove(&key);
+
+        if writer.is_empty() {
+            self.occupation.clear(idx);
+        }
+
+        self.count.fetch_add(-1, Ordering::Relaxed);
+
+        ret
+    }
+
+    pub fn foreach_lockfree<F: FnMut((&K, &V))>(&self, mut f: F) {
         //for bin_idx in 0..self.get_capacity() {
         for bin_idx in self.occupation.iter_set() {
             assert!(bin_idx < 1 << self.bin_scale, "Looked in too many bins");
@@ -133,10 +147,13 @@ impl<K: core::hash::Hash + Ord + Clone + Copy, V> CrashMap<K, V> {
 
             let mut lock = bin.write().unwrap();
 
-            lock.insert(key, insert());
+            // NB someone else may have beaten us to it while we were unlocked
+            if let Entry::Vacant(slot) = lock.entry(key) {
+                slot.insert(insert());
 
-            self.count.fetch_add(1, Ordering::Relaxed);
-            self.occupation.test_and_set(idx);
+                self.count.fetch_add(1, Ordering::Relaxed);
+                self.occupation.test_and_set(idx);
+            }
 
             let entry = lock.get(&key).unwrap();
             get(entry);
@@ -167,13 +184,13 @@ impl<K: core::hash::Hash + Ord + Clone + Copy> CrashSet<K> {
     pub fn is_empty(&self) -> bool { self.map.is_empty() }
     pub fn contains(&self, key: K) -> bool { self.map.contains_key(key) }
     pub fn insert(&self, key: K) -> bool {
-        self.map.insert(key, ()) != None
+        self.map.insert(key, ()).is_some()
     }
     pub fn remove(&self, key: K) -> bool {
-        self.map.remove(key) != None
+        self.map.remove(key).is_some()
     }
 
-    pub fn foreach_lockfree<F: FnMut(&K) -> ()>(&self, mut f: F) {
+    pub fn foreach_lockfree<F: FnMut(&K)>(&self, mut f: F) {
         self.map.foreach_lockfree(|(&k, &_)| f(&k));
     }
 }
@@ -217,6 +234,20 @@ fn test_map_iter() {
     assert!(results.contains(&(18, -18)));
 }
 
+#[test]
+fn test_map_remove_if() {
+    let map = CrashMap::with_capacity(1024);
+    map.insert(16, 32);
+
+    assert_eq!(map.remove_if(16, |&v| v > 100), None);
+    assert_eq!(map.remove_if(17, |_| true), None);
+    assert!(map.contains_key(16));
+
+    assert_eq!(map.remove_if(16, |&v| v == 32), Some(32));
+    assert!(!map.contains_key(16));
+    assert_eq!(map.len(), 0);
+}
+
 #[test]
 fn test_set_contains() {
     let set = CrashSet::with_capacity(1024);
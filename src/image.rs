@@ -1,51 +1,233 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
 use std::sync::atomic::{AtomicU8, Ordering};
 
-use crate::{atomicbitmask::AtomicBitMask, points::{SpacePoint, ColorPoint}};
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+
+use crate::{atomicbitmask::AtomicBitMask, canvas::{Canvas, MAX_DIMENSION}, points::{SpacePoint, ColorPoint}};
 
 pub struct Image {
-    r: Box<Vec<AtomicU8>>,
-    g: Box<Vec<AtomicU8>>,
-    b: Box<Vec<AtomicU8>>,
-    written: AtomicBitMask,
+    canvas: Canvas,
+    pixels: Pixels,
+}
+
+enum Pixels {
+    Dense {
+        r: Vec<AtomicU8>,
+        g: Vec<AtomicU8>,
+        b: Vec<AtomicU8>,
+        written: AtomicBitMask,
+    },
+    // Only what we've written, for canvases too big to allocate
+    Sparse(Mutex<FnvHashMap<SpacePoint, ColorPoint>>),
 }
 
 impl Image {
-    pub fn new() -> Image {
+    pub fn new(canvas: Canvas) -> Image {
+        let size = canvas.size();
+
         Image {
-            r: Box::new(std::iter::repeat_with(AtomicU8::default).take(4096*4096).collect()),
-            g: Box::new(std::iter::repeat_with(AtomicU8::default).take(4096*4096).collect()),
-            b: Box::new(std::iter::repeat_with(AtomicU8::default).take(4096*4096).collect()),
-            written: AtomicBitMask::new(4096*4096)
+            canvas,
+            pixels: Pixels::Dense {
+                r: std::iter::repeat_with(AtomicU8::default).take(size).collect(),
+                g: std::iter::repeat_with(AtomicU8::default).take(size).collect(),
+                b: std::iter::repeat_with(AtomicU8::default).take(size).collect(),
+                written: AtomicBitMask::new(size)
+            },
         }
     }
 
+    /// An image that only stores the pixels we write, so the canvas can be as big as we like
+    pub fn new_sparse(canvas: Canvas) -> Image {
+        Image {
+            canvas,
+            pixels: Pixels::Sparse(Mutex::new(FnvHashMap::default())),
+        }
+    }
+
+    pub fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.pixels, Pixels::Sparse(_))
+    }
+
     pub fn write(&self, space: &SpacePoint, color: &ColorPoint) {
-        //println!("wrote {}", space.offset());
-        let offset = space.offset();
-        let was_written = self.written.test_and_set(offset);
-        assert!(!was_written, "double write");
-        self.r[offset].store(color.r, Ordering::Relaxed);
-        self.g[offset].store(color.g, Ordering::Relaxed);
-        self.b[offset].store(color.b, Ordering::Relaxed);
-    }
-
-    pub fn to_raw(&self) -> Box<Vec<u8>> {
-        let mut ret = Box::new(vec![0u8; 4096*4096*4]);
-        for y in 0..4096 {
-            for x in 0..4096 {
-                let o = usize::try_from(y << 12 | x).expect("Should not index a point beyond 2^24");
-
-                ret[4 * o    ] = self.r[o].load(Ordering::Relaxed);
-                ret[4 * o + 1] = self.g[o].load(Ordering::Relaxed);
-                ret[4 * o + 2] = self.b[o].load(Ordering::Relaxed);
-                ret[4 * o + 3] = 255;
+        match &self.pixels {
+            Pixels::Dense { r, g, b, written } => {
+                let offset = self.canvas.space_offset(space);
+                let was_written = written.test_and_set(offset);
+                assert!(!was_written, "double write");
+                r[offset].store(color.r, Ordering::Relaxed);
+                g[offset].store(color.g, Ordering::Relaxed);
+                b[offset].store(color.b, Ordering::Relaxed);
+            }
+            Pixels::Sparse(pixels) => {
+                let was_written = pixels.lock().insert(*space, *color).is_some();
+                assert!(!was_written, "double write");
+            }
+        }
+    }
+
+    /// Every pixel of the canvas as RGBA, in row order
+    /// NB a sparse canvas can be far too big for this, so use to_raw_region with written_bounds there
+    pub fn to_raw(&self) -> Vec<u8> {
+        assert!(!self.is_sparse(), "Tried to get all of a sparse {} image, use to_raw_region instead", self.canvas);
+
+        self.to_raw_region(0, 0, self.canvas.width, self.canvas.height)
+    }
+
+    /// Like to_raw, but just for the given rectangle of the canvas
+    pub fn to_raw_region(&self, x: u32, y: u32, width: u32, height: u32) -> Vec<u8> {
+        assert!(
+            width > 0 && height > 0 && self.canvas.contains(x + width - 1, y + height - 1),
+            "Tried to get a {width}x{height} region at {x},{y} outside of {}", self.canvas
+        );
+
+        // Anything we never wrote (e.g. masked off) comes out transparent
+        let mut ret = vec![0u8; width as usize * height as usize * 4];
+        let mut put = |px: u32, py: u32, color: &ColorPoint| {
+            let offset = ((py - y) as usize * width as usize + (px - x) as usize) * 4;
+            ret[offset..offset + 4].copy_from_slice(&[color.r, color.g, color.b, 255]);
+        };
+
+        match &self.pixels {
+            Pixels::Dense { .. } => {
+                for py in y..y + height {
+                    for px in x..x + width {
+                        if let Some(color) = self.get(&SpacePoint::new(px, py)) {
+                            put(px, py, &color);
+                        }
+                    }
+                }
+            }
+            Pixels::Sparse(pixels) => {
+                // Just the one lock, and then only look at what's actually there
+                let pixels = pixels.lock();
+                for (space, color) in pixels.iter() {
+                    let (px, py) = space.xy();
+                    if (x..x + width).contains(&px) && (y..y + height).contains(&py) {
+                        put(px, py, color);
+                    }
+                }
             }
         }
 
         ret
     }
 
+    /// The color at the given space, if we've written it
+    pub fn get(&self, space: &SpacePoint) -> Option<ColorPoint> {
+        match &self.pixels {
+            Pixels::Dense { r, g, b, written } => {
+                let o = self.canvas.space_offset(space);
+                written.test(o).then(|| ColorPoint::new(
+                    r[o].load(Ordering::Relaxed),
+                    g[o].load(Ordering::Relaxed),
+                    b[o].load(Ordering::Relaxed),
+                ))
+            }
+            Pixels::Sparse(pixels) => pixels.lock().get(space).copied(),
+        }
+    }
+
+    /// The smallest rectangle holding everything we've written, as x, y, width, height
+    pub fn written_bounds(&self) -> Option<(u32, u32, u32, u32)> {
+        let mut bounds: Option<(u32, u32, u32, u32)> = None;
+        let mut include = |space: &SpacePoint| {
+            let (x, y) = space.xy();
+            bounds = Some(match bounds {
+                None => (x, y, x, y),
+                Some((lx, ly, ux, uy)) => (lx.min(x), ly.min(y), ux.max(x), uy.max(y)),
+            });
+        };
+
+        match &self.pixels {
+            Pixels::Dense { written, .. } => {
+                for o in (0..self.canvas.size()).filter(|&o| written.test(o)) {
+                    include(&self.canvas.space_at(o));
+                }
+            }
+            Pixels::Sparse(pixels) => pixels.lock().keys().for_each(include),
+        }
+
+        bounds.map(|(lx, ly, ux, uy)| (lx, ly, ux - lx + 1, uy - ly + 1))
+    }
+
     pub fn has(&self, position: usize) -> bool {
-        self.written.test(position)
+        self.get(&self.canvas.space_at(position)).is_some()
+    }
+}
+
+/// Reads in any PNG as 8-bit RGBA pixels in row order, along with its size
+/// Pixels from images without transparency are all opaque
+pub fn read_png(path: &Path) -> Result<(Canvas, Vec<[u8; 4]>), png::DecodingError> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info()?;
+    let (width, height) = reader.info().size();
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        let message = format!("{width}x{height} is bigger than any canvas (dimensions must be <= {MAX_DIMENSION})");
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+    }
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    let bytes = &buf[..info.buffer_size()];
+
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => bytes.chunks(4).map(|px| [px[0], px[1], px[2], px[3]]).collect(),
+        png::ColorType::Rgb => bytes.chunks(3).map(|px| [px[0], px[1], px[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => bytes.chunks(2).map(|px| [px[0], px[0], px[0], px[1]]).collect(),
+        png::ColorType::Grayscale => bytes.iter().map(|&v| [v, v, v, 255]).collect(),
+        // NB normalize_to_color8 expands these for us
+        png::ColorType::Indexed => unreachable!("Indexed PNGs should have been expanded"),
+    };
+
+    Ok((Canvas::new(info.width, info.height), pixels))
+}
+
+#[test]
+fn test_sparse_image_matches_dense() {
+    let canvas = Canvas::new(64, 32);
+    let dense = Image::new(canvas);
+    let sparse = Image::new_sparse(canvas);
+    assert_eq!(dense.written_bounds(), None);
+
+    for (x, y) in [(10, 5), (12, 7), (40, 20), (11, 6)] {
+        let color = ColorPoint::new(x as u8, y as u8, 1);
+        dense.write(&SpacePoint::new(x, y), &color);
+        sparse.write(&SpacePoint::new(x, y), &color);
     }
-}
\ No newline at end of file
+
+    assert_eq!(dense.to_raw(), sparse.to_raw_region(0, 0, 64, 32));
+    assert_eq!(sparse.written_bounds(), Some((10, 5, 31, 16)));
+    assert_eq!(dense.written_bounds(), sparse.written_bounds());
+
+    let region = sparse.to_raw_region(10, 5, 3, 3);
+    assert_eq!(&region[..4], &[10, 5, 1, 255]);
+    assert_eq!(&region[4..8], &[0, 0, 0, 0]);
+    assert_eq!(&region[32..], &[12, 7, 1, 255]);
+}
+
+#[test]
+#[should_panic(expected = "Tried to get all of a sparse")]
+fn test_sparse_image_to_raw_panics() {
+    Image::new_sparse(Canvas::new(MAX_DIMENSION, MAX_DIMENSION)).to_raw();
+}
+
+#[test]
+fn test_read_png_too_big() {
+    let path = std::env::temp_dir().join(format!("rust_colors_{}_too_big.png", std::process::id()));
+    let mut encoder = png::Encoder::new(File::create(&path).unwrap(), MAX_DIMENSION + 1, 1);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.write_header().unwrap().write_image_data(&vec![0; MAX_DIMENSION as usize + 1]).unwrap();
+
+    let result = read_png(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(result.unwrap_err().to_string().contains("65537x1 is bigger than any canvas"));
+}
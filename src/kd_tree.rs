@@ -0,0 +1,533 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use fnv::FnvHashMap;
+use parking_lot::RwLock;
+
+use crate::{points::{SpacePoint, Point, ColorPoint}, bounding_box::BoundingBox, color_metric::ColorMetric, color_space::{ColorSpace, Coords}, nn_search_3d::NnSearch3d, octree_leafy::{KNearest, LeafEntry, NearestSearch, RangeSearch}};
+
+/// A k-d tree over the points' coords, which splits wherever the points actually are
+/// Unlike OctreeLeafy this copes with the frontier bunching up into a few hues
+/// NB points are spread over several shards by space, each its own tree behind its own lock,
+/// so a mutation (or rebuild) only holds up searches of one shard
+pub struct KdTree {
+    shards: Vec<RwLock<KdInner>>,
+    total_points: AtomicUsize,
+    space: ColorSpace,
+    metric: ColorMetric,
+}
+
+struct KdInner {
+    // Every node we've made since the last rebuild, removed ones included
+    nodes: Vec<KdNode>,
+    root: Option<usize>,
+    // Where each point we hold lives in nodes
+    index: FnvHashMap<Point, usize>,
+    // How many points we hold at each space
+    spaces: FnvHashMap<SpacePoint, usize>,
+    // How many points we held as of the last rebuild
+    built_len: usize,
+}
+
+struct KdNode {
+    entry: LeafEntry,
+    // Which coord we split on, lower child is <= ours and upper child is >=
+    axis: usize,
+    children: [Option<usize>; 2],
+    // Removed nodes stay put to keep the tree's shape until the next rebuild
+    removed: bool,
+}
+
+// Don't bother rebuilding tiny trees
+const REBUILD_SLACK: usize = 64;
+
+// Enough that the searcher threads and the mutator rarely want the same one
+const SHARDS: usize = 8;
+
+impl KdTree {
+    pub fn new() -> KdTree {
+        Self::new_in(ColorSpace::default(), ColorMetric::default())
+    }
+
+    /// A tree that indexes (and searches) points by their coords in the given color space
+    pub fn new_in(space: ColorSpace, metric: ColorMetric) -> KdTree {
+        KdTree {
+            shards: (0..SHARDS).map(|_| RwLock::new(KdInner {
+                nodes: Vec::new(),
+                root: None,
+                index: FnvHashMap::default(),
+                spaces: FnvHashMap::default(),
+                built_len: 0,
+            })).collect(),
+            total_points: AtomicUsize::new(0),
+            space,
+            metric,
+        }
+    }
+
+    pub fn metric(&self) -> &ColorMetric {
+        &self.metric
+    }
+
+    pub fn color_space(&self) -> &ColorSpace {
+        &self.space
+    }
+
+    /// How deep our deepest node is, for keeping an eye on balance
+    pub fn depth(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().depth()).max().unwrap_or(0)
+    }
+
+    /// Which shard holds the points at a space
+    /// NB neighboring spaces land in different shards, so a run of frontier points is spread out
+    fn shard(&self, space: &SpacePoint) -> &RwLock<KdInner> {
+        let (x, y) = space.xy();
+        &self.shards[(x as usize + 3 * y as usize) % SHARDS]
+    }
+}
+
+impl Default for KdTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KdInner {
+    fn depth(&self) -> usize {
+        let mut deepest = 0;
+        let mut stack = self.root.map(|root| (root, 1)).into_iter().collect::<Vec<_>>();
+
+        while let Some((idx, depth)) = stack.pop() {
+            deepest = deepest.max(depth);
+            stack.extend(self.nodes[idx].children.iter().flatten().map(|&child| (child, depth + 1)));
+        }
+
+        deepest
+    }
+
+    /// Returns whether we didn't already have it
+    fn add(&mut self, entry: LeafEntry) -> bool {
+        if self.index.contains_key(&entry.point) {
+            // Already have it
+            return false;
+        }
+
+        let idx = self.nodes.len();
+        let mut axis = 0;
+
+        // Walk down to an empty slot
+        if let Some(mut at) = self.root {
+            loop {
+                let node = &self.nodes[at];
+                let side = (entry.coords[node.axis] >= node.entry.coords[node.axis]) as usize;
+                axis = (node.axis + 1) % 3;
+
+                match node.children[side] {
+                    Some(child) => at = child,
+                    None => {
+                        self.nodes[at].children[side] = Some(idx);
+                        break;
+                    }
+                }
+            }
+        } else {
+            self.root = Some(idx);
+        }
+
+        self.nodes.push(KdNode { entry, axis, children: [None, None], removed: false });
+        self.index.insert(entry.point, idx);
+        *self.spaces.entry(*entry.point.space()).or_default() += 1;
+
+        // We've at least doubled since we were balanced
+        if self.nodes.len() > 2 * self.built_len + REBUILD_SLACK {
+            self.rebuild();
+        }
+
+        true
+    }
+
+    /// Returns whether we had it
+    fn remove(&mut self, point: &Point) -> bool {
+        let Some(idx) = self.index.remove(point) else {
+            // Never had it
+            return false;
+        };
+
+        self.nodes[idx].removed = true;
+
+        let count = self.spaces.get_mut(point.space()).expect("Should count the spaces of points we hold");
+        *count -= 1;
+        if *count == 0 {
+            self.spaces.remove(point.space());
+        }
+
+        // Mostly dead weight now
+        let removed = self.nodes.len() - self.index.len();
+        if removed > self.index.len() + REBUILD_SLACK {
+            self.rebuild();
+        }
+
+        true
+    }
+
+    /// Rebuilds a balanced tree out of just the points we still hold
+    fn rebuild(&mut self) {
+        let mut entries = self.nodes.iter()
+            .filter(|node| !node.removed)
+            .map(|node| node.entry)
+            .collect::<Vec<_>>();
+
+        self.nodes.clear();
+        self.index.clear();
+        self.root = self.build(&mut entries, 0);
+        self.built_len = self.index.len();
+    }
+
+    fn build(&mut self, entries: &mut [LeafEntry], axis: usize) -> Option<usize> {
+        if entries.is_empty() {
+            return None;
+        }
+
+        // Split at the median, so each side gets half
+        let mid = entries.len() / 2;
+        entries.select_nth_unstable_by_key(mid, |e| e.coords[axis]);
+        let entry = entries[mid];
+
+        let idx = self.nodes.len();
+        self.nodes.push(KdNode { entry, axis, children: [None, None], removed: false });
+        self.index.insert(entry.point, idx);
+
+        let (lower, upper) = entries.split_at_mut(mid);
+        let next_axis = (axis + 1) % 3;
+        let lower = self.build(lower, next_axis);
+        let upper = self.build(&mut upper[1..], next_axis);
+        self.nodes[idx].children = [lower, upper];
+
+        Some(idx)
+    }
+
+    /// The first point we hold on the way down towards the coords, hopefully a good starting guess
+    fn first_entry_towards(&self, coords: &Coords) -> Option<LeafEntry> {
+        let mut found = None;
+        let mut at = self.root;
+
+        while let Some(idx) = at {
+            let node = &self.nodes[idx];
+            if !node.removed {
+                found = Some(node.entry);
+            }
+
+            let side = (coords[node.axis] >= node.entry.coords[node.axis]) as usize;
+            at = node.children[side];
+        }
+
+        // Everything along that path may have been removed
+        found.or_else(|| self.index.values().next().map(|&idx| self.nodes[idx].entry))
+    }
+
+    fn find_nearest_inner(&self, pt: &Coords, root_bounds: BoundingBox, search: &mut NearestSearch) {
+        let Some(root) = self.root else {
+            return;
+        };
+
+        // NB frontier colors tend to arrive in runs, so between rebuilds we could get deep, hence no recursion
+        let mut stack = vec![(root, root_bounds)];
+
+        while let Some((idx, bounds)) = stack.pop() {
+            if search.nearest_dist == 0 {
+                // Can't do better
+                return;
+            }
+
+            if !search.overlaps(&bounds) {
+                // Don't bother
+                continue;
+            }
+
+            let node = &self.nodes[idx];
+
+            if !node.removed && search.covers(&node.entry.coords) {
+                let dist = search.space.distance(&search.metric, &node.entry.coords, pt);
+
+                if dist < search.nearest_dist {
+                    search.nearest.clone_from(&node.entry.point);
+                    search.nearest_dist = dist;
+                    search.bounds.set_around_metric(pt, dist, &search.metric);
+                }
+            }
+
+            // Visit the side we're on first (so push it last)
+            let (lower, upper) = bounds.split(node.axis, node.entry.coords[node.axis]);
+            let halves = [(node.children[0], lower), (node.children[1], upper)];
+            let near = (pt[node.axis] >= node.entry.coords[node.axis]) as usize;
+
+            for (child, child_bounds) in [halves[1 - near], halves[near]] {
+                if let Some(child) = child {
+                    stack.push((child, child_bounds));
+                }
+            }
+        }
+    }
+
+    /// Hands every live node inside the search bounds to `visit`, the side nearer the center first
+    fn visit_range(&self, root_bounds: BoundingBox, search: &mut RangeSearch, mut visit: impl FnMut(&LeafEntry, &mut RangeSearch)) {
+        let Some(root) = self.root else {
+            return;
+        };
+
+        let mut stack = vec![(root, root_bounds)];
+
+        while let Some((idx, bounds)) = stack.pop() {
+            if !search.overlaps(&bounds) {
+                // Don't bother
+                continue;
+            }
+
+            let node = &self.nodes[idx];
+
+            if !node.removed {
+                visit(&node.entry, search);
+            }
+
+            let (lower, upper) = bounds.split(node.axis, node.entry.coords[node.axis]);
+            let halves = [(node.children[0], lower), (node.children[1], upper)];
+            let near = (search.center[node.axis] >= node.entry.coords[node.axis]) as usize;
+
+            for (child, child_bounds) in [halves[1 - near], halves[near]] {
+                if let Some(child) = child {
+                    stack.push((child, child_bounds));
+                }
+            }
+        }
+    }
+}
+
+impl NnSearch3d for KdTree {
+    fn has(&self, pt: &SpacePoint) -> bool {
+        self.shard(pt).read().spaces.contains_key(pt)
+    }
+
+    fn has_point(&self, pt: &Point) -> bool {
+        self.shard(pt.space()).read().index.contains_key(pt)
+    }
+
+    fn len(&self) -> usize {
+        self.total_points.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, point: Point, _spare_vectors: &mut Vec<Vec<Point>>) {
+        let coords = self.space.coords(point.color());
+
+        if self.shard(point.space()).write().add(LeafEntry { point, coords }) {
+            self.total_points.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn remove(&self, point: Point, _spare_vectors: &mut Vec<Vec<Point>>) {
+        if self.shard(point.space()).write().remove(&point) {
+            self.total_points.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn find_nearest(&self, color: &ColorPoint) -> Option<Point> {
+        let coords = self.space.coords(color);
+        let mut search: Option<NearestSearch> = None;
+
+        // NB we only hold one shard at a time, and whatever we found in one bounds the search of the next
+        for shard in &self.shards {
+            let inner = shard.read();
+
+            let search = match search.as_mut() {
+                Some(search) => search,
+                None => {
+                    // Grab a (hopefully nearby) starting point
+                    let Some(LeafEntry { point: nearest, coords: nearest_coords }) = inner.first_entry_towards(&coords) else {
+                        continue;
+                    };
+
+                    // The radius to beat is of course the distance to this starting point
+                    let nearest_dist = self.space.distance(&self.metric, &nearest_coords, &coords);
+                    let mut bounds = BoundingBox::new(0, 0, 0, 0, 0, 0);
+                    bounds.set_around_metric(&coords, nearest_dist, &self.metric);
+
+                    search.insert(NearestSearch {
+                        nearest,
+                        nearest_dist,
+                        bounds,
+                        metric: self.metric,
+                        space: self.space,
+                    })
+                }
+            };
+
+            if search.nearest_dist == 0 {
+                // We simply can't do better than that!
+                break;
+            }
+
+            inner.find_nearest_inner(&coords, self.space.bounds(), search);
+        }
+
+        search.map(|search| search.nearest)
+    }
+
+    fn find_k_nearest(&self, color: &ColorPoint, k: usize) -> Vec<Point> {
+        // Anything will do until we have k
+        let mut search = RangeSearch::new(self.space.coords(color), i32::MAX, self.space, self.metric);
+        let mut best = KNearest::new(k);
+
+        if k > 0 {
+            for shard in &self.shards {
+                shard.read().visit_range(self.space.bounds(), &mut search, |entry, search| {
+                    if let Some(dist) = search.distance_to(&entry.coords) {
+                        best.offer(dist, entry.point, search);
+                    }
+                });
+            }
+        }
+
+        best.into_points()
+    }
+
+    fn find_within(&self, color: &ColorPoint, radius_sq: i32) -> Vec<Point> {
+        let mut search = RangeSearch::new(self.space.coords(color), radius_sq, self.space, self.metric);
+        let mut found = Vec::new();
+
+        for shard in &self.shards {
+            shard.read().visit_range(self.space.bounds(), &mut search, |entry, search| {
+                if search.distance_to(&entry.coords).is_some() {
+                    found.push(entry.point);
+                }
+            });
+        }
+
+        found
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test]
+fn test_kd_tree_add_remove() {
+    let tree = KdTree::new();
+    let mut spare_vectors = Vec::new();
+    assert!(tree.is_empty());
+    assert_eq!(tree.find_nearest(&ColorPoint::new(0, 0, 0)), None);
+
+    let point = Point::new(SpacePoint::new(3, 4), ColorPoint::new(10, 20, 30));
+    tree.add(point, &mut spare_vectors);
+    tree.add(point, &mut spare_vectors);
+    assert_eq!(tree.len(), 1, "Adding the same point again shouldn't do anything");
+
+    assert!(tree.has_point(&point));
+    assert!(tree.has(point.space()));
+    assert_eq!(tree.find_nearest(&ColorPoint::new(255, 255, 255)), Some(point));
+
+    tree.remove(point, &mut spare_vectors);
+    tree.remove(point, &mut spare_vectors);
+    assert!(tree.is_empty());
+    assert!(!tree.has_point(&point));
+    assert!(!tree.has(point.space()));
+    assert_eq!(tree.find_nearest(&ColorPoint::new(10, 20, 30)), None);
+}
+
+#[test]
+fn test_kd_tree_find_nearest() {
+    // Churn through lots of points (and so rebuilds) and check against a brute force search
+    let rng = fastrand::Rng::with_seed(2020);
+    let random_color = || ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..));
+
+    let setups = [
+        (ColorSpace::Rgb, ColorMetric::Euclidean),
+        (ColorSpace::Rgb, ColorMetric::Manhattan),
+        (ColorSpace::Rgb, ColorMetric::weighted(3, 4, 2)),
+        (ColorSpace::OkLab, ColorMetric::Euclidean),
+        (ColorSpace::Hsv, ColorMetric::Euclidean),
+    ];
+
+    for (space, metric) in setups {
+        let tree = KdTree::new_in(space, metric);
+        let mut spare_vectors = Vec::new();
+        let mut held = Vec::new();
+
+        for i in 0..3000 {
+            // Mostly grow, but with plenty of removals
+            if held.is_empty() || rng.u8(..3) != 0 {
+                let point = Point::new(SpacePoint::new(i, 0), random_color());
+                tree.add(point, &mut spare_vectors);
+                held.push(point);
+            } else {
+                let point = held.swap_remove(rng.usize(..held.len()));
+                tree.remove(point, &mut spare_vectors);
+            }
+
+            if i % 10 != 0 {
+                continue;
+            }
+
+            assert_eq!(tree.len(), held.len());
+
+            let search_color = random_color();
+            let search_coords = space.coords(&search_color);
+            let control_dist = held.iter()
+                .map(|point| space.distance(&metric, &space.coords(point.color()), &search_coords))
+                .min()
+                .unwrap();
+
+            let nearest = tree.find_nearest(&search_color).expect("Nearest should be found");
+            let nearest_dist = space.distance(&metric, &space.coords(nearest.color()), &search_coords);
+
+            assert_eq!(nearest_dist, control_dist, "{space:?} {metric:?} search for {search_color:?} found {:?}", nearest.color());
+        }
+
+        // Rebuilds should keep us from getting lopsided
+        assert!(tree.depth() < 4 * (usize::BITS - held.len().leading_zeros()) as usize, "Got {} deep for {}", tree.depth(), held.len());
+    }
+}
+
+#[test]
+fn test_kd_tree_concurrent() {
+    // Mutators churning their own rows while searchers look on, like the generator's threads
+    let tree = KdTree::new();
+    let color_for = |x: u32, y: u32| ColorPoint::new((x * 7 % 256) as u8, (y * 40 % 256) as u8, (x * 13 % 256) as u8);
+
+    std::thread::scope(|scope| {
+        for y in 0..2 {
+            let tree = &tree;
+            scope.spawn(move || {
+                let mut spare_vectors = Vec::new();
+                for x in 0..2000 {
+                    tree.add(Point::new(SpacePoint::new(x, y), color_for(x, y)), &mut spare_vectors);
+
+                    // Leave every other one behind
+                    if x % 2 == 1 {
+                        tree.remove(Point::new(SpacePoint::new(x - 1, y), color_for(x - 1, y)), &mut spare_vectors);
+                    }
+                }
+            });
+        }
+
+        for searcher in 0..4 {
+            let tree = &tree;
+            scope.spawn(move || {
+                let rng = fastrand::Rng::with_seed(searcher);
+                for _ in 0..2000 {
+                    if let Some(found) = tree.find_nearest(&ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..))) {
+                        let (x, y) = found.space().xy();
+                        assert_eq!(found.color(), &color_for(x, y), "Found {found} which was never added");
+                    }
+                }
+            });
+        }
+    });
+
+    assert_eq!(tree.len(), 2000);
+    for y in 0..2 {
+        for x in 0..2000 {
+            let point = Point::new(SpacePoint::new(x, y), color_for(x, y));
+            assert_eq!(tree.has_point(&point), x % 2 == 1, "Should hold just the odd ones, but {point} says otherwise");
+            assert_eq!(tree.has(point.space()), x % 2 == 1);
+        }
+    }
+}
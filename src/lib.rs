@@ -1,9 +1,25 @@
 pub mod points;
+pub mod canvas;
+pub mod canvas_mask;
+pub mod space_mask;
+pub mod topology;
+pub mod volume;
 pub mod bounding_box;
+pub mod color_metric;
+pub mod color_space;
 pub mod nn_search_3d;
 pub mod octree;
 pub mod octree_leafy;
+pub mod kd_tree;
+pub mod uniform_grid;
+pub mod brute_force;
+pub mod nn_differential;
+pub mod nn_conformance;
 pub mod color_generator;
+pub mod palette;
+pub mod color_ordering;
+pub mod seed_layout;
+pub mod seed_map;
 pub mod atomicbitmask;
 pub mod image;
 pub mod crashmap;
\ No newline at end of file
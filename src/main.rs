@@ -1,13 +1,21 @@
 use std::time::Instant;
 use rust_colors::color_generator::ColorGenerator;
 
+const WIDTH: u32 = 4096;
+const HEIGHT: u32 = 4096;
 
 fn main() {
     let start = Instant::now();
     
     println!("Starting");
 
-    let mut generator = Box::new(ColorGenerator::new());
+    let mut generator = Box::new(ColorGenerator::new(WIDTH, HEIGHT));
+
+    // Pass a seed to reproduce an earlier run
+    if let Some(seed) = std::env::args().nth(1) {
+        generator.set_seed(seed.parse().expect("Seed should be a u64"));
+    }
+    println!("Using seed {}", generator.seed());
 
     let elapsed = start.elapsed();
     println!("Init Generator at {}", elapsed.as_millis());
@@ -16,15 +24,11 @@ fn main() {
     let elapsed = start.elapsed();
     println!("Shuffle at {}", elapsed.as_millis());
 
-    // whatever.read().unwrap().add_next_seed_pixel(1024, 1024);
-    // whatever.read().unwrap().add_next_seed_pixel(3072, 1024);
-    // whatever.read().unwrap().add_next_seed_pixel(1024, 3072);
-    // whatever.read().unwrap().add_next_seed_pixel(3072, 3072);
-    generator.add_next_seed_pixel(2048, 2048, &mut Vec::with_capacity(4));
+    generator.add_next_seed_pixel(WIDTH / 2, HEIGHT / 2, &mut Vec::with_capacity(4));
     let elapsed = start.elapsed();
     println!("Add seed at {}", elapsed.as_millis());
 
-    generator.grow_pixels_to(4096*4096);
+    generator.grow_pixels_to(WIDTH as usize * HEIGHT as usize);
     // generator.grow_pixels_to(16*4096);
     let elapsed = start.elapsed();
     println!("Grown at {}", elapsed.as_millis());
@@ -85,12 +89,12 @@ fn test_octree_add_remove_performance() {
             SpacePoint::new(i, i), 
             ColorPoint::new(rng.gen_range(0..=255), rng.gen_range(0..=255), rng.gen_range(0..=255))
         );
-        points.push(point.clone());
+        points.push(point);
         tree.add(point, &mut spare_vectors);
     }
 
     // Our vector pool
-    let mut spare_vectors = vec![Vec::with_capacity(8); 1024];
+    let mut spare_vectors: Vec<Vec<Point>> = (0..1024).map(|_| Vec::with_capacity(8)).collect();
 
     // Add/remove for a while
     let mut junk = 0;
@@ -109,11 +113,11 @@ fn test_octree_add_remove_performance() {
             SpacePoint::new(rng.gen_range(0..=4095), rng.gen_range(0..=4095)), 
             ColorPoint::new(rng.gen_range(0..=255), rng.gen_range(0..=255), rng.gen_range(0..=255))
         );
-        points.push(point.clone());
+        points.push(point);
         //tree.add_sync(point);
         tree.add(point, &mut spare_vectors);
 
         junk += tree.len() as u64;
     }
     println!("Junk: {}", junk);
-}
\ No newline at end of file
+}
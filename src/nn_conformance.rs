@@ -0,0 +1,241 @@
+use std::sync::Arc;
+
+use crate::{points::{SpacePoint, Point, ColorPoint}, nn_search_3d::NnSearch3d};
+
+/*
+    Tests written against NnSearch3d alone, so every implementation is held to the same semantics
+    Hand run_conformance_suite something that makes a fresh, empty RGB/Euclidean implementation
+*/
+
+/// Runs every check against fresh implementations from `make`, panicking on the first that fails
+pub fn run_conformance_suite(name: &str, make: &dyn Fn() -> Arc<dyn NnSearch3d>) {
+    check_empty(name, make().as_ref());
+    check_add_remove(name, make().as_ref());
+    check_duplicate_adds(name, make().as_ref());
+    check_absent_removes(name, make().as_ref());
+    check_shared_spaces(name, make().as_ref());
+    check_find_nearest(name, make().as_ref());
+    check_find_k_nearest(name, make().as_ref());
+    check_find_within(name, make().as_ref());
+}
+
+fn point(x: u32, color: (u8, u8, u8)) -> Point {
+    Point::new(SpacePoint::new(x, 0), ColorPoint::new(color.0, color.1, color.2))
+}
+
+fn check_empty(name: &str, tree: &dyn NnSearch3d) {
+    assert!(tree.is_empty(), "{name} should start empty");
+    assert_eq!(tree.len(), 0, "{name} should start empty");
+    assert_eq!(tree.find_nearest(&ColorPoint::new(1, 2, 3)), None, "{name} should find nothing when empty");
+    assert!(!tree.has(&SpacePoint::new(0, 0)), "{name} should have no spaces when empty");
+    assert!(!tree.has_point(&point(0, (0, 0, 0))), "{name} should have no points when empty");
+}
+
+fn check_add_remove(name: &str, tree: &dyn NnSearch3d) {
+    let mut spare_vectors = Vec::new();
+    let points = [point(1, (10, 20, 30)), point(2, (200, 100, 0)), point(3, (128, 128, 128))];
+
+    for (i, p) in points.iter().enumerate() {
+        tree.add(*p, &mut spare_vectors);
+        assert_eq!(tree.len(), i + 1, "{name} should count each added point");
+        assert!(tree.has_point(p) && tree.has(p.space()), "{name} should hold {p}");
+    }
+
+    tree.remove(points[1], &mut spare_vectors);
+    assert_eq!(tree.len(), 2, "{name} should count removals");
+    assert!(!tree.has_point(&points[1]) && !tree.has(points[1].space()), "{name} should have let go of {}", points[1]);
+    assert!(tree.has_point(&points[0]) && tree.has_point(&points[2]), "{name} should keep the rest");
+
+    for p in points {
+        tree.remove(p, &mut spare_vectors);
+    }
+    assert!(tree.is_empty(), "{name} should be empty after removing everything");
+    assert_eq!(tree.find_nearest(&ColorPoint::new(10, 20, 30)), None, "{name} should find nothing once emptied");
+}
+
+fn check_duplicate_adds(name: &str, tree: &dyn NnSearch3d) {
+    let mut spare_vectors = Vec::new();
+    let p = point(1, (50, 60, 70));
+
+    tree.add(p, &mut spare_vectors);
+    tree.add(p, &mut spare_vectors);
+    assert_eq!(tree.len(), 1, "{name} should ignore adding a point it has");
+
+    // One removal has to be enough
+    tree.remove(p, &mut spare_vectors);
+    assert!(tree.is_empty(), "{name} should not keep a second copy");
+    assert!(!tree.has_point(&p) && !tree.has(p.space()), "{name} should not keep a second copy");
+    assert_eq!(tree.find_nearest(&ColorPoint::new(50, 60, 70)), None, "{name} should not keep a second copy");
+}
+
+fn check_absent_removes(name: &str, tree: &dyn NnSearch3d) {
+    let mut spare_vectors = Vec::new();
+    let held = point(1, (50, 60, 70));
+
+    // Before anything is added, then a different color at the same space, then a close color elsewhere
+    tree.remove(held, &mut spare_vectors);
+    tree.add(held, &mut spare_vectors);
+    tree.remove(point(1, (51, 60, 70)), &mut spare_vectors);
+    tree.remove(point(2, (50, 60, 70)), &mut spare_vectors);
+
+    assert_eq!(tree.len(), 1, "{name} should ignore removing points it doesn't have");
+    assert!(tree.has_point(&held), "{name} should ignore removing points it doesn't have");
+    assert_eq!(tree.find_nearest(&ColorPoint::new(0, 0, 0)), Some(held), "{name} should still find {held}");
+
+    // And after it's already gone
+    tree.remove(held, &mut spare_vectors);
+    tree.remove(held, &mut spare_vectors);
+    assert_eq!(tree.len(), 0, "{name} should not go below empty");
+
+    tree.add(held, &mut spare_vectors);
+    assert_eq!(tree.len(), 1, "{name} should count properly after extra removals");
+    assert_eq!(tree.find_nearest(&ColorPoint::new(255, 255, 255)), Some(held), "{name} should count properly after extra removals");
+}
+
+fn check_shared_spaces(name: &str, tree: &dyn NnSearch3d) {
+    let mut spare_vectors = Vec::new();
+
+    // One frontier pixel waiting next to three colors
+    let dark = point(5, (10, 10, 10));
+    let mid = point(5, (128, 128, 128));
+    let light = point(5, (240, 240, 240));
+    for p in [dark, mid, light] {
+        tree.add(p, &mut spare_vectors);
+    }
+
+    assert_eq!(tree.len(), 3, "{name} should count each color at a space");
+    assert!(tree.has(dark.space()), "{name} should have the shared space");
+
+    tree.remove(mid, &mut spare_vectors);
+    assert_eq!(tree.len(), 2, "{name} should only remove the exact point");
+    assert!(tree.has_point(&dark) && tree.has_point(&light) && !tree.has_point(&mid), "{name} should only remove the exact point");
+    assert!(tree.has(dark.space()), "{name} should keep a space while anything is left there");
+    assert_eq!(tree.find_nearest(&ColorPoint::new(0, 0, 0)), Some(dark), "{name} should still find the others");
+    assert_eq!(tree.find_nearest(&ColorPoint::new(130, 130, 130)), Some(light), "{name} should not find the removed color");
+
+    tree.remove(dark, &mut spare_vectors);
+    tree.remove(light, &mut spare_vectors);
+    assert!(!tree.has(dark.space()), "{name} should drop a space once everything there is gone");
+    assert!(tree.is_empty(), "{name} should be empty after removing everything");
+}
+
+fn check_find_nearest(name: &str, tree: &dyn NnSearch3d) {
+    let mut spare_vectors = Vec::new();
+    let rng = fastrand::Rng::with_seed(23);
+    let random_color = || ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..));
+
+    let mut held = Vec::new();
+    for i in 0..500 {
+        let p = Point::new(SpacePoint::new(i % 64, i / 64), random_color());
+        tree.add(p, &mut spare_vectors);
+        held.push(p);
+    }
+
+    // Exact matches come back exactly
+    for p in held.iter().step_by(25) {
+        let found = tree.find_nearest(p.color()).expect("Should find something");
+        assert_eq!(found.color(), p.color(), "{name} should find an exact match for {p}");
+    }
+
+    for _ in 0..200 {
+        let color = random_color();
+        let best = held.iter().map(|p| p.color().distance_to(&color)).min().unwrap();
+        let found = tree.find_nearest(&color).expect("Should find something");
+
+        assert!(held.contains(&found), "{name} found {found} which it was never given");
+        assert_eq!(found.color().distance_to(&color), best, "{name} found {found} for {color} but something is nearer");
+    }
+}
+
+fn check_find_k_nearest(name: &str, tree: &dyn NnSearch3d) {
+    let mut spare_vectors = Vec::new();
+    let rng = fastrand::Rng::with_seed(24);
+    let random_color = || ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..));
+    assert!(tree.find_k_nearest(&random_color(), 3).is_empty(), "{name} should find nothing when empty");
+
+    let mut held = Vec::new();
+    for i in 0..500 {
+        let p = Point::new(SpacePoint::new(i % 64, i / 64), random_color());
+        tree.add(p, &mut spare_vectors);
+        held.push(p);
+    }
+
+    assert!(tree.find_k_nearest(&random_color(), 0).is_empty(), "{name} should find nothing when asked for nothing");
+    assert_eq!(tree.find_k_nearest(&random_color(), 600).len(), 500, "{name} should find everything when asked for more than it has");
+
+    for k in [1, 2, 5, 40] {
+        for _ in 0..50 {
+            let color = random_color();
+            let mut distances = held.iter().map(|p| p.color().distance_to(&color)).collect::<Vec<_>>();
+            distances.sort_unstable();
+
+            let found = tree.find_k_nearest(&color, k);
+            let found_distances = found.iter().map(|p| p.color().distance_to(&color)).collect::<Vec<_>>();
+
+            assert!(found.iter().all(|p| held.contains(p)), "{name} found points it was never given");
+            assert_eq!(found_distances, distances[..k], "{name} found the wrong {k} nearest to {color}");
+        }
+    }
+}
+
+fn check_find_within(name: &str, tree: &dyn NnSearch3d) {
+    let mut spare_vectors = Vec::new();
+    let rng = fastrand::Rng::with_seed(25);
+    let random_color = || ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..));
+
+    let mut held = Vec::new();
+    for i in 0..500 {
+        let p = Point::new(SpacePoint::new(i % 64, i / 64), random_color());
+        tree.add(p, &mut spare_vectors);
+        held.push(p);
+    }
+
+    // A radius of 0 is just exact matches
+    let exact = held[7];
+    assert_eq!(tree.find_within(exact.color(), 0), vec![exact], "{name} should find just {exact} at its own color");
+    assert_eq!(tree.count_within(&ColorPoint::new(0, 0, 0), i32::MAX), 500, "{name} should count everything within any distance");
+
+    for radius_sq in [1, 100, 2000, 20000, 200000] {
+        for _ in 0..50 {
+            let color = random_color();
+            let mut expected = held.iter().filter(|p| p.color().distance_to(&color) <= radius_sq).copied().collect::<Vec<_>>();
+            expected.sort_unstable();
+
+            let mut found = tree.find_within(&color, radius_sq);
+            found.sort_unstable();
+
+            assert_eq!(found, expected, "{name} found the wrong points within {radius_sq} of {color}");
+            assert_eq!(tree.count_within(&color, radius_sq), expected.len(), "{name} counted wrong within {radius_sq} of {color}");
+        }
+    }
+}
+
+#[test]
+fn test_octree_leafy_conforms() {
+    use crate::octree_leafy::OctreeLeafy;
+    run_conformance_suite("OctreeLeafy", &|| Arc::new(OctreeLeafy::init_tree(4)));
+}
+
+#[test]
+fn test_kd_tree_conforms() {
+    use crate::kd_tree::KdTree;
+    run_conformance_suite("KdTree", &|| Arc::new(KdTree::new()));
+}
+
+#[test]
+fn test_uniform_grid_conforms() {
+    use crate::uniform_grid::UniformGrid;
+    run_conformance_suite("UniformGrid", &|| Arc::new(UniformGrid::new()));
+}
+
+#[test]
+fn test_brute_force_conforms() {
+    use crate::brute_force::BruteForce;
+    run_conformance_suite("BruteForce", &|| Arc::new(BruteForce::new()));
+}
+
+#[test]
+fn test_legacy_octree_conforms() {
+    use crate::{bounding_box::BoundingBox, octree::Octree};
+    run_conformance_suite("Octree", &|| Octree::new(None, 0, 0, BoundingBox::new(0, 0, 0, 255, 255, 255)));
+}
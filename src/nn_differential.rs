@@ -0,0 +1,381 @@
+use std::{fmt, mem, panic::{self, AssertUnwindSafe}, sync::Arc};
+
+use crate::{points::{SpacePoint, Point, ColorPoint}, brute_force::BruteForce, color_metric::ColorMetric, color_space::ColorSpace, nn_search_3d::NnSearch3d};
+
+/*
+    Runs the same ops against an NnSearch3d and a BruteForce, and complains about the first place they disagree
+    Nearest searches only have to agree on distance, since ties can go to any of the equally near points
+*/
+
+/// One step of a differential run
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NnOp {
+    Add(Point),
+    Remove(Point),
+    FindNearest(ColorPoint),
+    FindKNearest(ColorPoint, usize),
+    FindWithin(ColorPoint, i32),
+    CountWithin(ColorPoint, i32),
+    Has(SpacePoint),
+    HasPoint(Point),
+    Len,
+}
+
+/// What a step gave back
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NnOutcome {
+    Done,
+    Nearest(Option<Point>),
+    Points(Vec<Point>),
+    Bool(bool),
+    Len(usize),
+    Panicked(String),
+}
+
+/// Which awkward cases random_ops may throw in, on top of plain adds, removes and searches
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OpMix {
+    /// Points that share a SpacePoint, like a frontier pixel next to several colors
+    pub shared_spaces: bool,
+    /// Adding a point that's already there
+    pub duplicate_adds: bool,
+    /// Removing a point that isn't there
+    pub absent_removes: bool,
+}
+
+/// Where an implementation first disagreed with BruteForce
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// As few ops as we could find that still go wrong, where the last one is the one that disagrees
+    pub ops: Vec<NnOp>,
+    pub expected: NnOutcome,
+    pub found: NnOutcome,
+}
+
+/// A long run of frontier-ish ops, where new colors tend to land near ones we already hold
+pub fn random_ops(rng: &fastrand::Rng, count: usize, mix: OpMix) -> Vec<NnOp> {
+    let random_color = || ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..));
+    let mut held: Vec<Point> = Vec::new();
+    let mut next_space = 0u32;
+    let mut ops = Vec::with_capacity(count);
+
+    while ops.len() < count {
+        let op = match rng.u8(..10) {
+            0..=3 if mix.duplicate_adds && !held.is_empty() && rng.u8(..8) == 0 => {
+                NnOp::Add(held[rng.usize(..held.len())])
+            }
+            0..=3 => {
+                let space = if mix.shared_spaces && !held.is_empty() && rng.bool() {
+                    *held[rng.usize(..held.len())].space()
+                } else {
+                    next_space += 1;
+                    SpacePoint::new(next_space % 4096, next_space / 4096)
+                };
+
+                let color = if !held.is_empty() && rng.bool() {
+                    let near = held[rng.usize(..held.len())].color();
+                    let nudge = |c: u8| c.saturating_add(rng.u8(..8)).saturating_sub(rng.u8(..8));
+                    ColorPoint::new(nudge(near.r), nudge(near.g), nudge(near.b))
+                } else {
+                    random_color()
+                };
+
+                let point = Point::new(space, color);
+                if held.contains(&point) && !mix.duplicate_adds {
+                    continue;
+                }
+
+                if !held.contains(&point) {
+                    held.push(point);
+                }
+
+                NnOp::Add(point)
+            }
+            4..=5 if mix.absent_removes && rng.u8(..8) == 0 => {
+                let point = Point::new(SpacePoint::new(4095, 4095), random_color());
+                if held.contains(&point) {
+                    continue;
+                }
+
+                NnOp::Remove(point)
+            }
+            4..=5 if !held.is_empty() => NnOp::Remove(held.swap_remove(rng.usize(..held.len()))),
+            4..=7 => NnOp::FindNearest(random_color()),
+            8 => match rng.u8(..3) {
+                0 => NnOp::FindKNearest(random_color(), rng.usize(..12)),
+                1 => NnOp::FindWithin(random_color(), rng.i32(0..3000)),
+                _ => NnOp::CountWithin(random_color(), rng.i32(0..3000)),
+            },
+            _ => match rng.u8(..3) {
+                0 if !held.is_empty() => NnOp::Has(*held[rng.usize(..held.len())].space()),
+                0 => NnOp::Has(SpacePoint::new(0, 0)),
+                1 if !held.is_empty() && rng.bool() => NnOp::HasPoint(held[rng.usize(..held.len())]),
+                1 => NnOp::HasPoint(Point::new(SpacePoint::new(1, 0), random_color())),
+                _ => NnOp::Len,
+            },
+        };
+
+        ops.push(op);
+    }
+
+    ops
+}
+
+/// Runs the ops against a fresh implementation from `make`, and if it ever disagrees with
+/// BruteForce, shrinks the ops down to a small sequence that still goes wrong the same way
+pub fn check(make: &dyn Fn() -> Arc<dyn NnSearch3d>, ops: &[NnOp], space: ColorSpace, metric: ColorMetric) -> Result<(), Divergence> {
+    let Some((step, _, _)) = first_divergence(make, ops, space, metric) else {
+        return Ok(());
+    };
+
+    // Only keep cuts that still fail on the same kind of op, so we don't wander off to some other problem
+    let kind = mem::discriminant(&ops[step]);
+    let mut ops = ops[..=step].to_vec();
+
+    // Cut out ever smaller chunks for as long as it keeps failing
+    // NB a later cut can make an earlier op unnecessary, so go again until nothing more comes out
+    loop {
+        let before = ops.len();
+        let mut chunk = ops.len().div_ceil(2);
+
+        while chunk > 0 {
+            let mut start = 0;
+            while start < ops.len() {
+                let end = (start + chunk).min(ops.len());
+                let candidate = [&ops[..start], &ops[end..]].concat();
+
+                match first_divergence(make, &candidate, space, metric) {
+                    Some((step, _, _)) if mem::discriminant(&candidate[step]) == kind => {
+                        ops = candidate[..=step].to_vec();
+                    }
+                    _ => start += chunk,
+                }
+            }
+
+            chunk /= 2;
+        }
+
+        if ops.len() == before {
+            break;
+        }
+    }
+
+    let (_, expected, found) = first_divergence(make, &ops, space, metric).expect("Shrunk ops should still diverge");
+    Err(Divergence { ops, expected, found })
+}
+
+/// The step, and what each side said, where we first disagree
+pub fn first_divergence(make: &dyn Fn() -> Arc<dyn NnSearch3d>, ops: &[NnOp], space: ColorSpace, metric: ColorMetric) -> Option<(usize, NnOutcome, NnOutcome)> {
+    let reference = BruteForce::new_in(space, metric);
+    let tree = make();
+
+    for (step, op) in ops.iter().enumerate() {
+        let expected = apply(&reference, op);
+        let found = apply(tree.as_ref(), op);
+
+        if !agrees(&reference, op, &expected, &found) {
+            return Some((step, expected, found));
+        }
+    }
+
+    None
+}
+
+fn apply(tree: &dyn NnSearch3d, op: &NnOp) -> NnOutcome {
+    let mut spare_vectors = Vec::new();
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| match op {
+        NnOp::Add(point) => { tree.add(*point, &mut spare_vectors); NnOutcome::Done }
+        NnOp::Remove(point) => { tree.remove(*point, &mut spare_vectors); NnOutcome::Done }
+        NnOp::FindNearest(color) => NnOutcome::Nearest(tree.find_nearest(color)),
+        NnOp::FindKNearest(color, k) => NnOutcome::Points(tree.find_k_nearest(color, *k)),
+        NnOp::FindWithin(color, radius_sq) => NnOutcome::Points(tree.find_within(color, *radius_sq)),
+        NnOp::CountWithin(color, radius_sq) => NnOutcome::Len(tree.count_within(color, *radius_sq)),
+        NnOp::Has(space) => NnOutcome::Bool(tree.has(space)),
+        NnOp::HasPoint(point) => NnOutcome::Bool(tree.has_point(point)),
+        NnOp::Len => NnOutcome::Len(tree.len()),
+    }));
+
+    outcome.unwrap_or_else(|err| {
+        let message = err.downcast_ref::<String>().cloned()
+            .or_else(|| err.downcast_ref::<&str>().map(|s| s.to_string()))
+            .unwrap_or_default();
+        NnOutcome::Panicked(message)
+    })
+}
+
+fn agrees(reference: &BruteForce, op: &NnOp, expected: &NnOutcome, found: &NnOutcome) -> bool {
+    match (op, expected, found) {
+        // Any equally near point we actually hold will do
+        (NnOp::FindNearest(color), NnOutcome::Nearest(Some(expected)), NnOutcome::Nearest(Some(found))) => {
+            reference.has_point(found) && reference.distance(found.color(), color) == reference.distance(expected.color(), color)
+        }
+        // Likewise the k nearest only have to be as near, one for one
+        (NnOp::FindKNearest(color, _), NnOutcome::Points(expected), NnOutcome::Points(found)) => {
+            let mut distinct = found.clone();
+            distinct.sort_unstable();
+            distinct.dedup();
+
+            distinct.len() == found.len() && found.len() == expected.len() && found.iter().zip(expected).all(|(found, expected)| {
+                reference.has_point(found) && reference.distance(found.color(), color) == reference.distance(expected.color(), color)
+            })
+        }
+        // In any order
+        (NnOp::FindWithin(..), NnOutcome::Points(expected), NnOutcome::Points(found)) => {
+            let (mut expected, mut found) = (expected.clone(), found.clone());
+            expected.sort_unstable();
+            found.sort_unstable();
+            expected == found
+        }
+        _ => expected == found,
+    }
+}
+
+impl fmt::Display for NnOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NnOp::Add(point) => write!(f, "add {} {}", point.space(), point.color()),
+            NnOp::Remove(point) => write!(f, "remove {} {}", point.space(), point.color()),
+            NnOp::FindNearest(color) => write!(f, "find_nearest {color}"),
+            NnOp::FindKNearest(color, k) => write!(f, "find_k_nearest {color} {k}"),
+            NnOp::FindWithin(color, radius_sq) => write!(f, "find_within {color} {radius_sq}"),
+            NnOp::CountWithin(color, radius_sq) => write!(f, "count_within {color} {radius_sq}"),
+            NnOp::Has(space) => write!(f, "has {space}"),
+            NnOp::HasPoint(point) => write!(f, "has_point {} {}", point.space(), point.color()),
+            NnOp::Len => write!(f, "len"),
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Diverged from brute force after {} ops:", self.ops.len())?;
+        for (step, op) in self.ops.iter().enumerate() {
+            writeln!(f, "  {step}: {op}")?;
+        }
+
+        write!(f, "Expected {:?} but found {:?}", self.expected, self.found)
+    }
+}
+
+#[cfg(test)]
+fn backends() -> Vec<(crate::nn_search_3d::SearchBackend, ColorSpace, ColorMetric)> {
+    use crate::nn_search_3d::SearchBackend;
+
+    let setups = [
+        (ColorSpace::Rgb, ColorMetric::Euclidean),
+        (ColorSpace::Rgb, ColorMetric::weighted(3, 4, 2)),
+        (ColorSpace::OkLab, ColorMetric::Manhattan),
+        (ColorSpace::Hsv, ColorMetric::Euclidean),
+    ];
+
+    [SearchBackend::Octree, SearchBackend::KdTree, SearchBackend::Grid]
+        .into_iter()
+        .flat_map(|backend| setups.map(|(space, metric)| (backend, space, metric)))
+        .collect()
+}
+
+#[test]
+fn test_backends_match_brute_force() {
+    let rng = fastrand::Rng::with_seed(2222);
+    let mix = OpMix { shared_spaces: true, duplicate_adds: true, absent_removes: true };
+
+    for (backend, space, metric) in backends() {
+        let ops = random_ops(&rng, 3000, mix);
+        let make = || -> Arc<dyn NnSearch3d> { backend.build(space, metric, Default::default()) };
+
+        if let Err(divergence) = check(&make, &ops, space, metric) {
+            panic!("{backend:?} in {space:?} with {metric:?}: {divergence}");
+        }
+    }
+}
+
+#[test]
+fn test_backends_match_brute_force_across_hue_seam() {
+    use crate::nn_search_3d::SearchBackend;
+
+    // A handful of reds either side of hue 0, searched for by more reds, so nearly every search crosses the seam
+    let rng = fastrand::Rng::with_seed(2226);
+    let near_red = || ColorPoint::new(rng.u8(240..), rng.u8(..16), rng.u8(..16));
+
+    for space in [ColorSpace::Hsv, ColorSpace::Hsl] {
+        for backend in [SearchBackend::Octree, SearchBackend::KdTree, SearchBackend::Grid] {
+            for _ in 0..50 {
+                let mut ops = (0..rng.u32(1..7)).map(|x| NnOp::Add(Point::new(SpacePoint::new(x, 0), near_red()))).collect::<Vec<_>>();
+                ops.extend((0..20).map(|_| NnOp::FindNearest(near_red())));
+
+                let make = || -> Arc<dyn NnSearch3d> { backend.build(space, ColorMetric::Euclidean, Default::default()) };
+
+                if let Err(divergence) = check(&make, &ops, space, ColorMetric::Euclidean) {
+                    panic!("{backend:?} in {space:?}: {divergence}");
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tie_breaks_match_brute_force() {
+    use crate::octree_leafy::{OctreeLeafy, TieBreak};
+
+    let rng = fastrand::Rng::with_seed(2225);
+    let mix = OpMix { shared_spaces: true, duplicate_adds: true, absent_removes: true };
+
+    for tie_break in [TieBreak::Random { seed: 3 }, TieBreak::Oldest, TieBreak::Newest] {
+        let ops = random_ops(&rng, 3000, mix);
+        let make = || -> Arc<dyn NnSearch3d> {
+            let mut tree = OctreeLeafy::init_tree_in(4, ColorSpace::Hsv, ColorMetric::Euclidean);
+            tree.set_tie_break(tie_break);
+            Arc::new(tree)
+        };
+
+        if let Err(divergence) = check(&make, &ops, ColorSpace::Hsv, ColorMetric::Euclidean) {
+            panic!("{tie_break:?}: {divergence}");
+        }
+    }
+}
+
+#[test]
+fn test_legacy_octree_matches_brute_force() {
+    use crate::{bounding_box::BoundingBox, octree::Octree};
+
+    // NB the legacy tree only does RGB
+    let rng = fastrand::Rng::with_seed(2223);
+    let ops = random_ops(&rng, 3000, OpMix { shared_spaces: true, duplicate_adds: true, absent_removes: true });
+    let make = || -> Arc<dyn NnSearch3d> { Octree::new(None, 0, 0, BoundingBox::new(0, 0, 0, 255, 255, 255)) };
+
+    if let Err(divergence) = check(&make, &ops, ColorSpace::Rgb, ColorMetric::Euclidean) {
+        panic!("Legacy octree: {divergence}");
+    }
+}
+
+#[test]
+fn test_divergences_shrink() {
+    // Something that forgets about dark colors, which should shrink right down to adding one and looking for it
+    struct Forgetful(BruteForce);
+
+    impl NnSearch3d for Forgetful {
+        fn add(&self, point: Point, spare_vectors: &mut Vec<Vec<Point>>) {
+            if point.color().r >= 32 {
+                self.0.add(point, spare_vectors)
+            }
+        }
+
+        fn remove(&self, point: Point, spare_vectors: &mut Vec<Vec<Point>>) { self.0.remove(point, spare_vectors) }
+        fn find_nearest(&self, pt: &ColorPoint) -> Option<Point> { self.0.find_nearest(pt) }
+        fn find_k_nearest(&self, pt: &ColorPoint, k: usize) -> Vec<Point> { self.0.find_k_nearest(pt, k) }
+        fn find_within(&self, pt: &ColorPoint, radius_sq: i32) -> Vec<Point> { self.0.find_within(pt, radius_sq) }
+        fn has(&self, pt: &SpacePoint) -> bool { self.0.has(pt) }
+        fn has_point(&self, pt: &Point) -> bool { self.0.has_point(pt) }
+        fn len(&self) -> usize { self.0.len() }
+        fn is_empty(&self) -> bool { self.0.is_empty() }
+    }
+
+    let rng = fastrand::Rng::with_seed(2224);
+    let ops = random_ops(&rng, 2000, OpMix::default());
+    let make = || -> Arc<dyn NnSearch3d> { Arc::new(Forgetful(BruteForce::new())) };
+
+    let divergence = check(&make, &ops, ColorSpace::Rgb, ColorMetric::Euclidean).expect_err("Forgetting colors should diverge");
+    assert_eq!(divergence.ops.len(), 2, "Should shrink down to an add and a lookup, got {divergence}");
+    assert!(matches!(divergence.ops[0], NnOp::Add(point) if point.color().r < 32));
+}
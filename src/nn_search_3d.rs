@@ -1,12 +1,90 @@
-use crate::points::{Point, ColorPoint, SpacePoint};
+use std::sync::Arc;
 
+use crate::{points::{Point, ColorPoint, SpacePoint}, color_metric::ColorMetric, color_space::ColorSpace, kd_tree::KdTree, octree_leafy::{OctreeLeafy, TieBreak}, uniform_grid::UniformGrid};
+
+/// A set of points we can search by color
+/// Points are whole (space, color) pairs, so several colors can wait at the same space
+/// See nn_conformance for the tests every implementation should pass
+/// NB `spare_vectors` is a pool callers lend to add and remove for reusing allocations, which implementations
+/// are free to ignore, callers can't count on anything being taken from or returned to it
 pub trait NnSearch3d {
+    /// Adding a point we already hold does nothing
     fn add(&self, point: Point, spare_vectors: &mut Vec<Vec<Point>>);
+    /// Removes just this point (not others at the same space), removing one we don't hold does nothing
     fn remove(&self, point: Point, spare_vectors: &mut Vec<Vec<Point>>);
+    /// Any one of the nearest points to the color, or None if we're empty
     fn find_nearest(&self, pt: &ColorPoint) -> Option<Point>;
+    /// Up to k of the nearest points to the color, nearest first, where ties at the end can go either way
+    fn find_k_nearest(&self, pt: &ColorPoint, k: usize) -> Vec<Point>;
+    /// Every point within `radius_sq` of the color (inclusive) in no particular order
+    /// NB this is as measured by our metric, which for the Euclidean ones is squared
+    fn find_within(&self, pt: &ColorPoint, radius_sq: i32) -> Vec<Point>;
+    /// How many points find_within would give back
+    fn count_within(&self, pt: &ColorPoint, radius_sq: i32) -> usize {
+        self.find_within(pt, radius_sq).len()
+    }
 
+    /// Whether we hold any point at this space
     fn has(&self, pt: &SpacePoint) -> bool;
     fn has_point(&self, pt: &Point) -> bool;
+    /// How many distinct points we hold
     fn len(&self) -> usize;
     fn is_empty(&self) -> bool;
-}
\ No newline at end of file
+}
+
+/// Which NnSearch3d the generator keeps its frontier in
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum SearchBackend {
+    /// OctreeLeafy, evenly subdivided to a fixed depth
+    #[default]
+    Octree,
+    /// KdTree, which splits wherever the points are
+    KdTree,
+    /// UniformGrid, 32^3 equal cells searched in shells
+    Grid,
+}
+
+impl SearchBackend {
+    /// Whether our searches can break ties this way, only the octree can do any but FirstFound
+    pub fn supports(&self, tie_break: &TieBreak) -> bool {
+        *tie_break == TieBreak::FirstFound || *self == SearchBackend::Octree
+    }
+
+    /// An empty frontier that searches in the given color space
+    pub fn build(&self, space: ColorSpace, metric: ColorMetric, tie_break: TieBreak) -> Arc<dyn NnSearch3d + Send + Sync> {
+        assert!(self.supports(&tie_break), "Tried to break ties by {tie_break:?} with the {self:?} backend, only the octree can do that");
+
+        match self {
+            SearchBackend::Octree => {
+                let mut tree = OctreeLeafy::init_tree_in(4, space, metric);
+                tree.set_tie_break(tie_break);
+                Arc::new(tree)
+            }
+            SearchBackend::KdTree => Arc::new(KdTree::new_in(space, metric)),
+            SearchBackend::Grid => Arc::new(UniformGrid::new_in(32, space, metric)),
+        }
+    }
+}
+
+// NB not a real benchmark, just a manual timing harness that prints how long each backend takes
+// There are no warmups, repeats or assertions, so run it in release with
+// `cargo test --release -- --ignored --nocapture test_search_backend_performance` and compare by eye
+#[test]
+#[ignore = "manual timing harness, run with --ignored --nocapture to compare the backends"]
+fn test_search_backend_performance() {
+    use std::time::Instant;
+    use crate::color_generator::ColorGenerator;
+
+    // Grow the same real frontier with each backend and compare
+    for backend in [SearchBackend::Octree, SearchBackend::KdTree, SearchBackend::Grid] {
+        let mut generator = ColorGenerator::new(256, 256);
+        generator.set_seed(20);
+        generator.set_search_backend(backend);
+        generator.shuffle_colors();
+        generator.add_next_seed_pixel(128, 128, &mut Vec::with_capacity(4));
+
+        let start = Instant::now();
+        generator.grow_all();
+        println!("{backend:?} grew {} pixels in {}ms", generator.pixels_placed(), start.elapsed().as_millis());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{borrow::Borrow, sync::{Weak, Arc}, ops::Deref};
+use std::{sync::{Weak, Arc, atomic::{AtomicUsize, Ordering}}, ops::Deref};
 
 use crate::{points::{ColorPoint, Point, SpacePoint}, bounding_box::BoundingBox, crashmap::{CrashMap}, nn_search_3d::NnSearch3d};
 use parking_lot::RwLock;
@@ -25,6 +25,8 @@ pub struct Octree {
   //point_lookup: Arc<CrashMap<usize, RwLock<Vec<Arc<Point>>>>>,
   //points: Arc<CrashSet<Arc<Point>>>,
   points: Arc<CrashMap<SpacePoint, PointBucket>>,
+  // NB points only counts spaces, this is every point in all of them
+  total_points: AtomicUsize,
   coord: usize,
   ptr: RwLock<Weak<Octree>>,
 }
@@ -58,6 +60,7 @@ impl Octree {
       //point_lookup: Arc::new(CrashMap::with_capacity(1024)),
       //points: Arc::new(CrashSet::with_capacity(1024)),
       points: Arc::new(CrashMap::with_capacity(256 >> (3 * depth))), // Heuristic
+      total_points: AtomicUsize::new(0),
       coord,
       ptr: RwLock::new(Weak::new()),
     });
@@ -72,20 +75,28 @@ impl Octree {
 
   
 
-  // Like remove but we already have all the color/space info
+  // Takes out just this point, from us and then the child it went down to
   fn remove_spec(&self, point: Point) {
-    // Try to remove, if we didn't have it then we're done
+    // NB check and remove under the bucket's lock, so only one of several removes of the same point counts
+    let removed = self.points.get(point.space(), |colors| {
+      let mut colors = colors.write();
+      let before = colors.len();
+      colors.retain(|p| p != &point);
+      colors.len() < before
+    });
 
-    //println!("  Remove spec {} {} at {} with {} in {}", point.space, point.color.offset(), self.depth, self.len(), self.bounds);
-    
-    //println!("    Removed {point} at {}", self.depth);
-    if self.depth > 0 { // NB we already removed it from the root...
-      // Note: this is very fine because we might have already removed this space point
-      self.points.remove(*point.space());
+    if removed != Some(true) {
+      // Never had it, so neither do our children
+      return;
     }
 
+    // Nothing else waiting here, unless someone added to it since
+    self.points.remove_if(*point.space(), |colors| colors.read().is_empty());
+
+    self.total_points.fetch_sub(1, Ordering::Relaxed);
+
     // Remove from appropriate child
-    if let Some(child) = self.get_child(&point.color()) {
+    if let Some(child) = self.get_child(point.color()) {
       child.remove_spec(point)
     }
   }
@@ -101,20 +112,12 @@ impl Octree {
     *child = match child.as_ref() {
       Some(c) => Some(c.to_owned()),
       None => {
-        // Calculate new bounds
-        let bounds = &self.bounds;
-        let clr = if i32::from(color.r) > bounds.lr + radius { bounds.lr + radius } else { bounds.lr };
-        let cur = if i32::from(color.r) < bounds.ur - radius { bounds.ur - radius } else { bounds.ur };
-        let clg = if i32::from(color.g) > bounds.lg + radius { bounds.lg + radius } else { bounds.lg };
-        let cug = if i32::from(color.g) < bounds.ug - radius { bounds.ug - radius } else { bounds.ug };
-        let clb = if i32::from(color.b) > bounds.lb + radius { bounds.lb + radius } else { bounds.lb };
-        let cub = if i32::from(color.b) < bounds.ub - radius { bounds.ub - radius } else { bounds.ub };
-
         let child = Octree::new(
           Some(Weak::clone(&self.ptr.read())),
           self.depth + 1,
           self.coord | caddr << (18 - 3 * self.depth),
-          BoundingBox::new(clr, clg, clb, cur, cug, cub),
+          // NB this has to match addr, colors right on the midpoint go in the upper half
+          self.bounds.sub_for_idx(caddr, radius),
         );
 
         Some(child)
@@ -170,10 +173,7 @@ impl Octree {
     //   println!("Did {what}");
     // }
 
-    match best {
-      Some(p) => Some(p),
-      None => None
-    }
+    best
   }
 
   fn nn_search_up(&self, mut search: Search, from: Arc<Octree>) -> Option<Search> {
@@ -200,7 +200,6 @@ impl Octree {
         .upgrade()
         .expect("depth > 0 parent should not have been deleted")
         .as_ref()
-        .borrow()
         .nn_search_up(search, self.ptr.read().upgrade().expect("should have self"))?;
     }
 
@@ -214,10 +213,10 @@ impl Octree {
     // We have no points to search
     if self.points.is_empty() { return Some(search); }
 
-    if self.points.len() <= QUAD_TUNING {
-      // We have few enough points, search here
+    if self.points.len() <= QUAD_TUNING || self.depth >= TREE_TUNING_DEPTH {
+      // We have few enough points (or no children to hand off to), search here
       let our_nearest = self.nearest_in_self(&search.source)?;
-      let nearest_dist = search.source.distance_to(&our_nearest.color());
+      let nearest_dist = search.source.distance_to(our_nearest.color());
 
       if nearest_dist < search.best_distance_sq {
         // New candidate!
@@ -225,7 +224,7 @@ impl Octree {
         search.best_distance_sq = nearest_dist;
         search.bounds.set_around(&search.source, f64::from(nearest_dist).sqrt().floor() as i32);
       }
-    } else if self.depth < TREE_TUNING_DEPTH {
+    } else {
       // Keep going down!
       
       for child in &self.children {
@@ -243,11 +242,11 @@ impl Octree {
 
 impl NnSearch3d for Octree {
   fn len(&self) -> usize {
-    self.points.len()
+    self.total_points.load(Ordering::Relaxed)
   }
 
   fn is_empty(&self) -> bool {
-    self.points.is_empty()
+    self.len() == 0
   }
 
   fn has(&self, pt: &SpacePoint) -> bool {
@@ -255,25 +254,19 @@ impl NnSearch3d for Octree {
   }
 
   fn has_point(&self, pt: &Point) -> bool {
-    self.points.get(&pt.space(), |colors| {
+    self.points.get(pt.space(), |colors| {
       colors.read().contains(pt)
     }).unwrap_or(false)
   }
 
+  // NB leaves don't pool anything yet, but the pool is passed all the way down for when they do
+  #[allow(clippy::only_used_in_recursion)]
   fn add(&self, point: Point, spare_vectors: &mut Vec<Vec<Point>>) {
     //println!("    Add {point} at {}", self.depth);
-    
-    if self.depth < TREE_TUNING_DEPTH {
-      // Head downwards
-      // NB Probably it is important for thread-ness that we add to our children first?
-      // But what if we add to child, search find, start removing, and we haven't gotten back to the root yet?
-      // TODO add another bitvec for "available" to do the search retry things?
-      self.get_or_create_child(&point.color()).add(point, spare_vectors);
-    }
-
-    //println!("Adding {} {} at {} with {} in {}", &point.space, point.color.offset(), self.depth, self.len(), self.bounds);
 
     // Add the point here
+    // NB check and add under the bucket's lock, so two threads adding the same point can't both get in
+    let mut added = false;
     self.points.get_or_insert(
       *point.space(),
       // Grab us some space if we didn't have this list yet
@@ -283,40 +276,36 @@ impl NnSearch3d for Octree {
         PointBucket(RwLock::new(Vec::with_capacity(4)))
       },
       #[inline(never)]
-      move |p| {
-        p.write().push(point);
+      |p| {
+        let mut colors = p.write();
+        if !colors.contains(&point) {
+          colors.push(point);
+          added = true;
+        }
       }
     );
-      
-    assert!(self.len() > 0);
-    
-  }
 
-  fn remove(&self, point: Point, _spare_vectors: &mut Vec<Vec<Point>>) {
-    if !self.has_point(&point) {
-      panic!("Removing non-existent point {point}");
+    if !added {
+      // Already have it, and so do our children
+      return;
     }
 
-    //println!("Remove {} at {} with {}", point.space, self.depth, self.len());
-
-    // NB we are removing by the spatial component here, so get all the actual points with this
-    // Grab all our Rcs to remove
-    let pts_maybe = self.points.remove(*point.space());
+    self.total_points.fetch_add(1, Ordering::Relaxed);
 
-    //println!("    Removing {} instances of color {}", pts.borrow().len(), &point.color);
-    if let Some(pts) = pts_maybe {
-      //assert!(self.has_point(point), "Removing from list but not in lookup");
+    //println!("Adding {} {} at {} with {} in {}", &point.space, point.color.offset(), self.depth, self.len(), self.bounds);
 
-      for rc in pts.read().iter() {
-        // Remove from self
-        self.remove_spec(*rc);
-      }
+    if self.depth < TREE_TUNING_DEPTH {
+      // Head downwards
+      // NB we add here first now, so a search might briefly see us holding it before our child does
+      self.get_or_create_child(point.color()).add(point, spare_vectors);
     }
+  }
 
-    //assert!(!self.point_lookup.contains_key(&point.space), "Tried to remove a point but still present");
-    //assert!(!self.points.contains_key(point), "Tried to remove a point but still present");
+  fn remove(&self, point: Point, _spare_vectors: &mut Vec<Vec<Point>>) {
+    //println!("Remove {} at {} with {}", point.space, self.depth, self.len());
 
-    //pts_maybe
+    // NB other points at this space stay put
+    self.remove_spec(point);
   }
 
   fn find_nearest(&self, color: &ColorPoint) -> Option<Point> {
@@ -340,16 +329,14 @@ impl NnSearch3d for Octree {
       let ret = self.nearest_in_self(color)?;
 
       let distance = ret.color().distance_to(color);
-      let radius_sq = self.radius() * self.radius();
+      let search_radius = f64::from(distance).sqrt().floor() as i32;
 
-      if self.depth > 0 && distance > radius_sq {
-        // The distance to the nearest candidate is bigger than our own radius
+      if self.depth > 0 && distance > 0 && !self.bounds.contains(&BoundingBox::from_around(color, search_radius)) {
+        // Something nearer than our candidate could be outside of us
         // Therefore, we need to search our neighbors too
-        let search_radius = f64::from(distance).sqrt().floor() as i32;
-
         let mut search = Search {
           candidate: ret,
-          source: color.clone(),
+          source: *color,
           best_distance_sq: distance,
           bounds: BoundingBox::from_around(color, search_radius)
         };
@@ -359,7 +346,6 @@ impl NnSearch3d for Octree {
           .upgrade()
           .expect("depth > 0 should have a non-deleted parent")
           .as_ref()
-          .borrow()
           .nn_search_up(search, self.ptr.read().upgrade().expect("should have self"))?;
 
         return Some(search.candidate);
@@ -367,7 +353,65 @@ impl NnSearch3d for Octree {
 
       Some(ret)
     } else {
-      child?.as_ref().borrow().find_nearest(color)
+      child?.as_ref().find_nearest(color)
     }
   }
-}
\ No newline at end of file
+
+  // NB these just check everything we hold, the legacy tree is only kept around for comparison
+
+  fn find_k_nearest(&self, color: &ColorPoint, k: usize) -> Vec<Point> {
+    let mut by_distance = Vec::with_capacity(self.len());
+    self.points.foreach_lockfree(|(_, points)| {
+      by_distance.extend(points.read().iter().map(|p| (p.color().distance_to(color), *p)));
+    });
+
+    by_distance.sort_unstable();
+    by_distance.into_iter().take(k).map(|(_, p)| p).collect()
+  }
+
+  fn find_within(&self, color: &ColorPoint, radius_sq: i32) -> Vec<Point> {
+    let mut found = Vec::new();
+    self.points.foreach_lockfree(|(_, points)| {
+      found.extend(points.read().iter().filter(|p| p.color().distance_to(color) <= radius_sq));
+    });
+
+    found
+  }
+}
+
+#[test]
+fn test_octree_concurrent_duplicates() {
+  // Several threads adding, then removing, the very same points should count each once
+  let tree = Octree::new(None, 0, 0, BoundingBox::new(0, 0, 0, 255, 255, 255));
+  let points = (0..500u32)
+    .map(|i| Point::new(SpacePoint::new(i % 50, i / 50), ColorPoint::new((i * 7 % 256) as u8, (i * 31 % 256) as u8, (i % 256) as u8)))
+    .collect::<Vec<_>>();
+
+  std::thread::scope(|scope| {
+    for _ in 0..4 {
+      scope.spawn(|| {
+        let mut spare_vectors = Vec::new();
+        for point in &points {
+          tree.add(*point, &mut spare_vectors);
+        }
+      });
+    }
+  });
+
+  assert_eq!(tree.len(), points.len(), "Racing adds of the same points should only count them once");
+  assert!(points.iter().all(|point| tree.has_point(point)));
+
+  std::thread::scope(|scope| {
+    for _ in 0..4 {
+      scope.spawn(|| {
+        let mut spare_vectors = Vec::new();
+        for point in &points {
+          tree.remove(*point, &mut spare_vectors);
+        }
+      });
+    }
+  });
+
+  assert_eq!(tree.len(), 0, "Racing removes of the same points should only count them once");
+  assert!(tree.find_nearest(&ColorPoint::new(0, 0, 0)).is_none());
+}
@@ -1,11 +1,25 @@
-use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+use std::{collections::BinaryHeap, hash::{Hash, Hasher}, sync::{Arc, atomic::{AtomicU64, AtomicUsize, Ordering}}};
 
-use integer_sqrt::IntegerSquareRoot;
+use fnv::FnvHasher;
 use parking_lot::RwLock;
 
-use crate::{points::{SpacePoint, Point, ColorPoint}, bounding_box::BoundingBox, nn_search_3d::NnSearch3d};
+use crate::{points::{SpacePoint, Point, ColorPoint}, bounding_box::BoundingBox, color_metric::ColorMetric, color_space::{ColorSpace, Coords}, nn_search_3d::NnSearch3d};
 
-type LeafBucket = Vec<Point>;
+/// A point along with where it sits in the tree's color space, so we only convert it once
+#[derive(Clone, Copy, Debug)]
+pub struct LeafEntry {
+    pub point: Point,
+    pub coords: Coords,
+}
+
+/// What a leaf holds, an entry stamped with when it was added
+#[derive(Clone, Copy, Debug)]
+pub struct LeafSlot {
+    pub entry: LeafEntry,
+    pub added: u64,
+}
+
+type LeafBucket = Vec<LeafSlot>;
 type LeafBucketWrapper = Arc<RwLock<LeafBucket>>;
 
 /*
@@ -14,11 +28,36 @@ type LeafBucketWrapper = Arc<RwLock<LeafBucket>>;
     We also have locks on the leaf nodes, but this should not be contentious?
 */
 
-pub enum OctreeLeafy {
+/// An octree with fixed-depth, pre-allocated nodes, and all of the points in the leaves
+pub struct OctreeLeafy {
+    root: OctreeNode,
+    space: ColorSpace,
+    metric: ColorMetric,
+    tie_break: TieBreak,
+    // Stamps for new points, so ties can go to the oldest or newest
+    next_added: AtomicU64,
+}
+
+/// Which of several equally near points a nearest search gives back
+/// NB anything but FirstFound has to look at every exact match, which is a bit slower
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum TieBreak {
+    /// Whichever we come across first, which follows the tree's traversal order
+    #[default]
+    FirstFound,
+    /// Any of them with equal odds, drawn from an rng started from the seed and the color we're searching for
+    /// NB so the same search on the same tree always picks the same way, whichever thread runs it
+    Random { seed: u64 },
+    /// The one that's been in the tree longest
+    Oldest,
+    /// The one added to the tree most recently
+    Newest,
+}
+
+enum OctreeNode {
     Node {
-        children: [Box<OctreeLeafy>; 8],
+        children: [Box<OctreeNode>; 8],
         bounds: BoundingBox,
-        depth: usize,
         total_points: AtomicUsize,
     },
     Leaf {
@@ -28,115 +67,298 @@ pub enum OctreeLeafy {
     },
 }
 
-struct NearestSearch {
+/// The state of a nearest neighbor search, shared with the other trees
+pub(crate) struct NearestSearch {
     pub nearest: Point,
     pub nearest_dist: i32,
     pub bounds: BoundingBox,
+    pub metric: ColorMetric,
+    pub space: ColorSpace,
+}
+
+impl NearestSearch {
+    #[inline]
+    pub(crate) fn overlaps(&self, bounds: &BoundingBox) -> bool {
+        overlaps_in(&self.space, &self.bounds, bounds)
+    }
+
+    #[inline]
+    pub(crate) fn covers(&self, coords: &Coords) -> bool {
+        covers_in(&self.space, &self.bounds, coords)
+    }
+}
+
+// NB search bounds may hang off the ends of a wrapping hue axis, so these check the other side too
+
+#[inline]
+fn overlaps_in(space: &ColorSpace, search_bounds: &BoundingBox, bounds: &BoundingBox) -> bool {
+    match space.wrap_period() {
+        None => search_bounds.intersects(bounds),
+        Some(period) => search_bounds.intersects_wrapping(bounds, period),
+    }
+}
+
+#[inline]
+fn covers_in(space: &ColorSpace, search_bounds: &BoundingBox, coords: &Coords) -> bool {
+    match space.wrap_period() {
+        None => search_bounds.contains_coords(coords),
+        Some(period) => search_bounds.contains_coords_wrapping(coords, period),
+    }
+}
+
+/// How a nearest search is breaking ties so far
+struct Ties {
+    tie_break: TieBreak,
+    // How many points at the nearest distance we've seen, and when the one we're holding was added
+    seen: u32,
+    added: u64,
+    rng: Option<fastrand::Rng>,
+}
+
+impl Ties {
+    /// Forget about any ties, as something nearer came along
+    #[inline]
+    fn clear(&mut self) {
+        self.seen = 0;
+    }
+
+    /// Whether a point at the nearest distance should replace the one we're holding
+    /// NB the first one we see always wins, as the starting guess may not be at this distance
+    #[inline]
+    fn prefers(&mut self, added: u64) -> bool {
+        self.seen += 1;
+
+        let prefer = self.seen == 1 || match self.tie_break {
+            TieBreak::FirstFound => false,
+            // Each of the n we've seen gets to be held with 1/n odds
+            TieBreak::Random { .. } => self.rng.as_ref().expect("Random ties should have an rng").u32(..self.seen) == 0,
+            TieBreak::Oldest => added < self.added,
+            TieBreak::Newest => added > self.added,
+        };
+
+        if prefer {
+            self.added = added;
+        }
+
+        prefer
+    }
+}
+
+/// The state of a search for everything within some distance of a center, shared with the other trees
+/// k nearest searches start out unbounded and shrink the radius as they go
+pub(crate) struct RangeSearch {
+    pub center: Coords,
+    pub radius: i32,
+    pub bounds: BoundingBox,
+    pub metric: ColorMetric,
+    pub space: ColorSpace,
+}
+
+impl RangeSearch {
+    pub(crate) fn new(center: Coords, radius: i32, space: ColorSpace, metric: ColorMetric) -> RangeSearch {
+        assert!(radius >= 0, "Tried to search within a negative distance {radius}");
+
+        let mut bounds = BoundingBox::new(0, 0, 0, 0, 0, 0);
+        bounds.set_around_metric(&center, radius, &metric);
+
+        RangeSearch { center, radius, bounds, metric, space }
+    }
+
+    pub(crate) fn shrink_to(&mut self, radius: i32) {
+        self.radius = radius;
+        self.bounds.set_around_metric(&self.center, radius, &self.metric);
+    }
+
+    #[inline]
+    pub(crate) fn overlaps(&self, bounds: &BoundingBox) -> bool {
+        overlaps_in(&self.space, &self.bounds, bounds)
+    }
+
+    /// How far the coords are from the center, if they're within our radius
+    #[inline]
+    pub(crate) fn distance_to(&self, coords: &Coords) -> Option<i32> {
+        if !covers_in(&self.space, &self.bounds, coords) {
+            // Quickly exclude if outside the search area
+            return None;
+        }
+
+        let dist = self.space.distance(&self.metric, coords, &self.center);
+        (dist <= self.radius).then_some(dist)
+    }
+
+    /// Whether everything inside the bounds is within our radius
+    pub(crate) fn encloses(&self, bounds: &BoundingBox) -> bool {
+        // NB going the short way around a wrapping axis is never further, so the far corner is still an upper bound
+        let far = |c: i32, l: i32, u: i32| (c - l).abs().max((u - c).abs());
+        let [c0, c1, c2] = self.center;
+
+        self.metric.delta_distance(far(c0, bounds.lr, bounds.ur), far(c1, bounds.lg, bounds.ug), far(c2, bounds.lb, bounds.ub)) <= self.radius
+    }
+}
+
+/// The nearest points a search has found so far, which bounds the search once there are k of them
+pub(crate) struct KNearest {
+    k: usize,
+    // Furthest on top, so it's the first to go
+    best: BinaryHeap<(i32, Point)>,
+}
+
+impl KNearest {
+    pub(crate) fn new(k: usize) -> KNearest {
+        KNearest { k, best: BinaryHeap::with_capacity(k + 1) }
+    }
+
+    #[inline]
+    pub(crate) fn offer(&mut self, dist: i32, point: Point, search: &mut RangeSearch) {
+        if self.best.len() < self.k {
+            self.best.push((dist, point));
+        } else if self.best.peek().is_some_and(|&(furthest, _)| dist < furthest) {
+            self.best.pop();
+            self.best.push((dist, point));
+        } else {
+            return;
+        }
+
+        if self.best.len() == self.k {
+            // Nothing further than our worst can make it in now
+            search.shrink_to(self.best.peek().expect("Should have k points").0);
+        }
+    }
+
+    /// Nearest first
+    pub(crate) fn into_points(self) -> Vec<Point> {
+        self.best.into_sorted_vec().into_iter().map(|(_, point)| point).collect()
+    }
 }
 
 impl OctreeLeafy {
     pub fn init_tree(depth: usize) -> OctreeLeafy {
+        Self::init_tree_with_metric(depth, ColorMetric::default())
+    }
+
+    pub fn init_tree_with_metric(depth: usize, metric: ColorMetric) -> OctreeLeafy {
+        Self::init_tree_in(depth, ColorSpace::default(), metric)
+    }
+
+    /// A tree that indexes (and searches) points by their coords in the given color space
+    pub fn init_tree_in(depth: usize, space: ColorSpace, metric: ColorMetric) -> OctreeLeafy {
         assert!(depth > 0, "Tried to init tree with depth 0 (must be > 0");
         assert!(depth < 8, "Tried to init tree with depth {depth} (must be < 8)");
         //assert!(depth < 4, "Tried to init tree with depth {depth} (must be < 4 as we're aggressive with pre-allocations atm)");
 
-        Self::init_node(0, depth, BoundingBox::new(
-            0, 0, 0, 255, 255, 255,
-        ))
+        OctreeLeafy {
+            root: OctreeNode::init_node(depth, space.bounds()),
+            space,
+            metric,
+            tie_break: TieBreak::default(),
+            next_added: AtomicU64::new(0),
+        }
+    }
+
+    /// Switches how we pick among equally near points, for searches from here on
+    pub fn set_tie_break(&mut self, tie_break: TieBreak) {
+        self.tie_break = tie_break;
+    }
+
+    pub fn tie_break(&self) -> &TieBreak {
+        &self.tie_break
+    }
+
+    pub fn metric(&self) -> &ColorMetric {
+        &self.metric
+    }
+
+    pub fn color_space(&self) -> &ColorSpace {
+        &self.space
     }
 
-    fn init_node(depth: usize, remaining_depth: usize, bounding_box: BoundingBox) -> OctreeLeafy {
+    // Testing how we might do the recursion part on child threads and just the final write on the main thread
+    pub fn precalc_path(&self, point: Point) -> LeafBucketWrapper {
+        self.root.precalc_path(&self.space.coords(point.color()))
+    }
+}
+
+impl OctreeNode {
+    fn init_node(remaining_depth: usize, bounding_box: BoundingBox) -> OctreeNode {
         if remaining_depth == 0 {
-            OctreeLeafy::Leaf {
+            OctreeNode::Leaf {
                 points: Arc::new(RwLock::new(Vec::new())),
                 bounds: bounding_box,
                 total_points: AtomicUsize::new(0),
             }
         } else {
-            let sub_radius = 128 >> depth;
-
-            OctreeLeafy::Node {
+            OctreeNode::Node {
                 // Subdivision packing is RGB ---, --+, -+-, -++, +--, +-+, ++-, +++
                 children: [
-                    Box::new(Self::init_node(depth + 1, remaining_depth - 1, bounding_box.sub_for_idx(0, sub_radius))),
-                    Box::new(Self::init_node(depth + 1, remaining_depth - 1, bounding_box.sub_for_idx(1, sub_radius))),
-                    Box::new(Self::init_node(depth + 1, remaining_depth - 1, bounding_box.sub_for_idx(2, sub_radius))),
-                    Box::new(Self::init_node(depth + 1, remaining_depth - 1, bounding_box.sub_for_idx(3, sub_radius))),
-                    Box::new(Self::init_node(depth + 1, remaining_depth - 1, bounding_box.sub_for_idx(4, sub_radius))),
-                    Box::new(Self::init_node(depth + 1, remaining_depth - 1, bounding_box.sub_for_idx(5, sub_radius))),
-                    Box::new(Self::init_node(depth + 1, remaining_depth - 1, bounding_box.sub_for_idx(6, sub_radius))),
-                    Box::new(Self::init_node(depth + 1, remaining_depth - 1, bounding_box.sub_for_idx(7, sub_radius))),
+                    Box::new(Self::init_node(remaining_depth - 1, bounding_box.half_for_idx(0))),
+                    Box::new(Self::init_node(remaining_depth - 1, bounding_box.half_for_idx(1))),
+                    Box::new(Self::init_node(remaining_depth - 1, bounding_box.half_for_idx(2))),
+                    Box::new(Self::init_node(remaining_depth - 1, bounding_box.half_for_idx(3))),
+                    Box::new(Self::init_node(remaining_depth - 1, bounding_box.half_for_idx(4))),
+                    Box::new(Self::init_node(remaining_depth - 1, bounding_box.half_for_idx(5))),
+                    Box::new(Self::init_node(remaining_depth - 1, bounding_box.half_for_idx(6))),
+                    Box::new(Self::init_node(remaining_depth - 1, bounding_box.half_for_idx(7))),
                 ],
                 bounds: bounding_box,
-                depth,
                 total_points: 0.into(),
             }
         }
     }
 
-    pub fn radius(depth: usize) -> i32 { 
-        128 >> depth
-    }
-
-    fn addr(depth: usize, color: &ColorPoint) -> usize {
+    fn addr(bounds: &BoundingBox, [c0, c1, c2]: &Coords) -> usize {
         // Subdivision packing is RGB ---, --+, -+-, -++, +--, +-+, ++-, +++
-        let mask = Self::radius(depth);
-        let over = 7 - depth;
-    
-        let addr_red = (color.r as i32 & mask) >> over;
-        let addr_green = (color.g as i32 & mask) >> over;
-        let addr_blue = (color.b as i32 & mask) >> over;
+        let [m0, m1, m2] = bounds.midpoint();
+
+        let addr_red = (c0 >= &m0) as usize;
+        let addr_green = (c1 >= &m1) as usize;
+        let addr_blue = (c2 >= &m2) as usize;
     
-        (addr_red << 2 | addr_green << 1 | addr_blue) as usize
+        addr_red << 2 | addr_green << 1 | addr_blue
     }
 
-    fn child_for(&self, color: &ColorPoint) -> Option<&OctreeLeafy> {
+    fn child_for(&self, coords: &Coords) -> Option<&OctreeNode> {
         match self {
-            OctreeLeafy::Node { children, depth, .. } => {
-                let addr = Self::addr(*depth, color);
+            OctreeNode::Node { children, bounds, .. } => {
+                let addr = Self::addr(bounds, coords);
                 Some(&children[addr])
             }
-            OctreeLeafy::Leaf { .. } => {
+            OctreeNode::Leaf { .. } => {
                 None
             }
         }
     }
 
     /// Grabs the first point we can find below us
-    fn first_point(&self) -> Option<Point> {
+    fn first_entry(&self) -> Option<LeafEntry> {
         if self.is_empty() {
             return None;
         }
 
         match self {
-            OctreeLeafy::Node { children, .. } => {
+            OctreeNode::Node { children, .. } => {
                 children.iter()
-                    .find_map(|child| child.first_point())
+                    .find_map(|child| child.first_entry())
             }
-            OctreeLeafy::Leaf { points, .. } => {
+            OctreeNode::Leaf { points, .. } => {
                 points.read()
                     .first()
-                    .cloned()
+                    .map(|slot| slot.entry)
             }
         }
     }
 
-    fn intersects(&self, bounds: &BoundingBox) -> bool {
+    fn bounds(&self) -> &BoundingBox {
         match self {
-            OctreeLeafy::Node { bounds: node_bounds, .. } => {
-                node_bounds.intersects(bounds)
-            }
-            OctreeLeafy::Leaf { bounds: leaf_bounds, .. } => {
-                leaf_bounds.intersects(bounds)
-            }
+            OctreeNode::Node { bounds, .. } => bounds,
+            OctreeNode::Leaf { bounds, .. } => bounds,
         }
     }
 
     #[inline(never)]
-    fn find_nearest_inner(&self, pt: &ColorPoint, search: &mut NearestSearch) {
+    fn find_nearest_inner(&self, pt: &Coords, search: &mut NearestSearch, ties: &mut Ties) {
         match self {
-            OctreeLeafy::Node { children, .. } => Self::find_nearest_inner_node(pt, children, search),
-            OctreeLeafy::Leaf { points, .. } => Self::find_nearest_inner_leaf(pt, points, search),
+            OctreeNode::Node { children, .. } => Self::find_nearest_inner_node(pt, children, search, ties),
+            OctreeNode::Leaf { points, .. } => Self::find_nearest_inner_leaf(pt, points, search, ties),
         }
     }
 
@@ -144,73 +366,136 @@ impl OctreeLeafy {
     // TODO something with cfg_attr
 
     #[inline(never)]
-    fn find_nearest_inner_node(pt: &ColorPoint, children: &[Box<OctreeLeafy>; 8], search: &mut NearestSearch) {
+    fn find_nearest_inner_node(pt: &Coords, children: &[Box<OctreeNode>; 8], search: &mut NearestSearch, ties: &mut Ties) {
         for child in children {
-            if child.is_empty() || !child.intersects(&search.bounds) {
+            if child.is_empty() || !search.overlaps(child.bounds()) {
                 // Don't bother
                 continue;
             }
 
-            child.find_nearest_inner(pt, search);
+            child.find_nearest_inner(pt, search, ties);
         }
     }
 
     #[inline(never)]
-    fn find_nearest_inner_leaf(pt: &ColorPoint, points: &LeafBucketWrapper, search: &mut NearestSearch) {
+    fn find_nearest_inner_leaf(pt: &Coords, points: &LeafBucketWrapper, search: &mut NearestSearch, ties: &mut Ties) {
         // Check all of our points and update the search if we find a better one
-        // If we have some equal points, choose a random one?
-        // let mut candidates = Vec::with_capacity(4);
-
-        for point in points.read().iter() {
+        for LeafSlot { entry, added } in points.read().iter() {
 
-            if !search.bounds.contains_color(point.color()) {
+            if !search.covers(&entry.coords) {
                 // Quickly exclude if outside the search area
                 continue;
             }
 
-            let dist = point.color().distance_to(pt);
-
-            if dist == 0 {
-                // This is it
-                search.nearest.clone_from(point);
-                search.nearest_dist = 0;
-                return;
-            }
+            let dist = search.space.distance(&search.metric, &entry.coords, pt);
 
-            if dist == search.nearest_dist {
-                // candidates.push(point.clone());
+            if dist > search.nearest_dist {
+                continue;
             }
 
             if dist < search.nearest_dist {
-                search.nearest.clone_from(point);
                 search.nearest_dist = dist;
-                let dist_actual = dist.integer_sqrt();
                 //search.bounds.set_around(pt, f64::from(search.nearest_dist).sqrt().floor() as i32);
-                search.bounds.set_around(pt, dist_actual);
+                search.bounds.set_around_metric(pt, dist, &search.metric);
+                ties.clear();
+            }
+
+            if ties.prefers(*added) {
+                search.nearest.clone_from(&entry.point);
 
-                // candidates.clear();
-                // candidates.push(point.clone());
+                if dist == 0 && ties.tie_break == TieBreak::FirstFound {
+                    // This is it
+                    return;
+                }
             }
         }
+    }
 
-        // if candidates.len() > 1 {
-        //     // Choose a random one
-        //     search.nearest = candidates.choose(&mut rand::thread_rng()).unwrap().clone();
-        // }
+    fn find_k_nearest_inner(&self, search: &mut RangeSearch, best: &mut KNearest) {
+        if self.is_empty() || !search.overlaps(self.bounds()) {
+            // Don't bother
+            return;
+        }
+
+        match self {
+            OctreeNode::Node { children, bounds, .. } => {
+                // Head towards the center first, so the radius shrinks as soon as possible
+                let toward = Self::addr(bounds, &search.center);
+                children[toward].find_k_nearest_inner(search, best);
+
+                for (idx, child) in children.iter().enumerate() {
+                    if idx != toward {
+                        child.find_k_nearest_inner(search, best);
+                    }
+                }
+            }
+            OctreeNode::Leaf { points, .. } => {
+                for LeafSlot { entry, .. } in points.read().iter() {
+                    if let Some(dist) = search.distance_to(&entry.coords) {
+                        best.offer(dist, entry.point, search);
+                    }
+                }
+            }
+        }
     }
 
-    // Testing how we might do the recursion part on child threads and just the final write on the main thread
-    pub fn precalc_path(&self, point: Point) -> LeafBucketWrapper {
+    fn find_within_inner(&self, search: &RangeSearch, found: &mut Vec<Point>) {
+        if self.is_empty() || !search.overlaps(self.bounds()) {
+            // Don't bother
+            return;
+        }
+
+        match self {
+            OctreeNode::Node { children, .. } => {
+                for child in children {
+                    child.find_within_inner(search, found);
+                }
+            }
+            OctreeNode::Leaf { points, .. } => {
+                found.extend(
+                    points.read()
+                        .iter()
+                        .filter(|slot| search.distance_to(&slot.entry.coords).is_some())
+                        .map(|slot| slot.entry.point)
+                );
+            }
+        }
+    }
+
+    fn count_within_inner(&self, search: &RangeSearch) -> usize {
+        if self.is_empty() || !search.overlaps(self.bounds()) {
+            // Don't bother
+            return 0;
+        }
+
+        if search.encloses(self.bounds()) {
+            // Everything here counts, no need to look
+            return self.len();
+        }
+
+        match self {
+            OctreeNode::Node { children, .. } => {
+                children.iter().map(|child| child.count_within_inner(search)).sum()
+            }
+            OctreeNode::Leaf { points, .. } => {
+                points.read()
+                    .iter()
+                    .filter(|slot| search.distance_to(&slot.entry.coords).is_some())
+                    .count()
+            }
+        }
+    }
+
+    fn precalc_path(&self, coords: &Coords) -> LeafBucketWrapper {
         let mut at = self;
-        let color = &point.color();
 
         loop {
             match at {
-                OctreeLeafy::Node { .. } => {
+                OctreeNode::Node { .. } => {
                     // Descend
-                    at = at.child_for(color).unwrap();
+                    at = at.child_for(coords).unwrap();
                 }
-                OctreeLeafy::Leaf { points, .. } => {
+                OctreeNode::Leaf { points, .. } => {
                     // No more to do
                     return points.clone();
                 }
@@ -220,80 +505,134 @@ impl OctreeLeafy {
     }
 }
 
-impl NnSearch3d for OctreeLeafy {
+impl OctreeNode {
     fn has(&self, pt: &SpacePoint) -> bool {
         match self {
-            OctreeLeafy::Node { children, .. } => {
+            OctreeNode::Node { children, .. } => {
                 children.iter().any(|child| child.has(pt))
             }
-            OctreeLeafy::Leaf { points, .. } => {
-                points.read().iter().any(|p| p.space() == pt)
+            OctreeNode::Leaf { points, .. } => {
+                points.read().iter().any(|slot| slot.entry.point.space() == pt)
             }
         }
     }
 
-    fn has_point(&self, pt: &Point) -> bool {
+    fn has_point(&self, pt: &Point, coords: &Coords) -> bool {
         match self {
-            OctreeLeafy::Node { .. } => {
+            OctreeNode::Node { .. } => {
                 // Go to child by color
-                self.child_for(&pt.color()).unwrap().has_point(pt)
+                self.child_for(coords).unwrap().has_point(pt, coords)
             }
-            OctreeLeafy::Leaf { points, .. } => {
+            OctreeNode::Leaf { points, .. } => {
                 points.read()
                     .iter()
-                    .any(|p| p == pt)
+                    .any(|slot| &slot.entry.point == pt)
             }
         }
     }
 
     fn len(&self) -> usize {
         match self {
-            OctreeLeafy::Node { total_points, .. } => total_points.load(Ordering::Relaxed), // XXX
-            OctreeLeafy::Leaf { total_points, .. } => total_points.load(Ordering::Relaxed), // XXX
+            OctreeNode::Node { total_points, .. } => total_points.load(Ordering::Relaxed), // XXX
+            OctreeNode::Leaf { total_points, .. } => total_points.load(Ordering::Relaxed), // XXX
         }
     }
 
-    fn add(&self, point: Point, spare_vectors: &mut Vec<Vec<Point>>) {
+    /// Returns whether we didn't already have it
+    // NB leaves don't pool anything yet, but the pool is passed all the way down for when they do
+    #[allow(clippy::only_used_in_recursion)]
+    fn add(&self, slot: LeafSlot, spare_vectors: &mut Vec<Vec<Point>>) -> bool {
         match self {
-            OctreeLeafy::Node { ref total_points, .. } => {
+            OctreeNode::Node { ref total_points, .. } => {
                 // Materialize that we added a point
+                // NB before we really do, so a search never skips us while it's in a leaf
                 total_points.fetch_add(1, Ordering::Relaxed); // XXX
                 // Add to child by color
-                let child = self.child_for(&point.color()).unwrap();
-                child.add(point, spare_vectors);
+                let child = self.child_for(&slot.entry.coords).unwrap();
+                let added = child.add(slot, spare_vectors);
+
+                if !added {
+                    // We already had it, so take that back
+                    total_points.fetch_sub(1, Ordering::Relaxed); // XXX
+                }
+
+                added
             }
-            OctreeLeafy::Leaf { points, total_points, .. } => {
+            OctreeNode::Leaf { points, total_points, .. } => {
                 let mut lock = points.write();
-                // TODO check we don't already have it? we shouldn't
-                lock.push(point);
+                if lock.iter().any(|held| held.entry.point == slot.entry.point) {
+                    return false;
+                }
+
+                lock.push(slot);
                 total_points.fetch_add(1, Ordering::Relaxed); // XXX
+                true
             }
         }
     }
 
-    fn remove(&self, point: Point, spare_vectors: &mut Vec<Vec<Point>>) {
+    /// Returns how many points we removed, which is 0 if we never had it
+    #[allow(clippy::only_used_in_recursion)]
+    fn remove(&self, point: Point, coords: &Coords, spare_vectors: &mut Vec<Vec<Point>>) -> usize {
         match self {
-            OctreeLeafy::Node { ref total_points, .. } => {
-                // Materialize that we removed a point
-                total_points.fetch_sub(1, Ordering::Relaxed); // XXX
+            OctreeNode::Node { ref total_points, .. } => {
                 // Remove from child by color
-                let child = self.child_for(&point.color()).unwrap();
-                child.remove(point, spare_vectors);
+                let child = self.child_for(coords).unwrap();
+                let removed = child.remove(point, coords, spare_vectors);
+
+                // Materialize that we removed a point
+                total_points.fetch_sub(removed, Ordering::Relaxed); // XXX
+                removed
             }
-            OctreeLeafy::Leaf { points, total_points, .. } => {
-                
-                let before = points.read().len();
-                points.write().retain(|p| p != &point);
-                total_points.fetch_sub(before - points.read().len(), Ordering::Relaxed); // XXX
-                
+            OctreeNode::Leaf { points, total_points, .. } => {
+                let mut lock = points.write();
+                let Some(idx) = lock.iter().position(|slot| slot.entry.point == point) else {
+                    return 0;
+                };
+
+                lock.remove(idx);
+                total_points.fetch_sub(1, Ordering::Relaxed); // XXX
+                1
             }
         }
     }
 
+    #[inline(never)]
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl NnSearch3d for OctreeLeafy {
+    fn has(&self, pt: &SpacePoint) -> bool {
+        self.root.has(pt)
+    }
+
+    fn has_point(&self, pt: &Point) -> bool {
+        self.root.has_point(pt, &self.space.coords(pt.color()))
+    }
+
+    fn len(&self) -> usize {
+        self.root.len()
+    }
+
+    fn add(&self, point: Point, spare_vectors: &mut Vec<Vec<Point>>) {
+        let coords = self.space.coords(point.color());
+        let added = self.next_added.fetch_add(1, Ordering::Relaxed);
+        self.root.add(LeafSlot { entry: LeafEntry { point, coords }, added }, spare_vectors);
+    }
+
+    fn remove(&self, point: Point, spare_vectors: &mut Vec<Vec<Point>>) {
+        let coords = self.space.coords(point.color());
+        self.root.remove(point, &coords, spare_vectors);
+    }
+
     fn find_nearest(&self, color: &ColorPoint) -> Option<Point> {
+        let coords = self.space.coords(color);
+
         // Start with the smallest node around our target that contains any point
-        let mut at = self;
-        while let Some(next) = at.child_for(color) {
+        let mut at = &self.root;
+        while let Some(next) = at.child_for(&coords) {
             // Nothing at or below us, so we're done
             if next.is_empty() {
                 break;
@@ -303,32 +642,72 @@ impl NnSearch3d for OctreeLeafy {
         }
 
         // Grab the (hopefully nearby) starting point
-        let Some(nearest) = at.first_point() else { return None };
-        let nearest_dist = nearest.color().distance_to(color);
+        let LeafEntry { point: nearest, coords: nearest_coords } = at.first_entry()?;
+        let nearest_dist = self.space.distance(&self.metric, &nearest_coords, &coords);
 
-        if nearest_dist == 0 {
+        if nearest_dist == 0 && self.tie_break == TieBreak::FirstFound {
             // We simply can't do better than that!
             return Some(nearest);
         }
 
         // Set the search bounds to be around the starting point
         // The radius to beat is of course the distance to this starting point
-        let bounds = BoundingBox::from_around(color, f64::from(nearest_dist).sqrt().floor() as i32);
+        let mut bounds = BoundingBox::new(0, 0, 0, 0, 0, 0);
+        bounds.set_around_metric(&coords, nearest_dist, &self.metric);
         
         let mut search = NearestSearch {
             nearest,
             nearest_dist,
             bounds,
+            metric: self.metric,
+            space: self.space,
         };
 
-        self.find_nearest_inner(color, &mut search);
+        let mut ties = Ties {
+            tie_break: self.tie_break,
+            seen: 0,
+            added: 0,
+            rng: match self.tie_break {
+                TieBreak::Random { seed } => {
+                    let mut hasher = FnvHasher::with_key(seed);
+                    color.hash(&mut hasher);
+                    Some(fastrand::Rng::with_seed(hasher.finish()))
+                }
+                _ => None,
+            },
+        };
+
+        self.root.find_nearest_inner(&coords, &mut search, &mut ties);
         
         Some(search.nearest)
     }
 
-    #[inline(never)]
+    fn find_k_nearest(&self, color: &ColorPoint, k: usize) -> Vec<Point> {
+        // Anything will do until we have k
+        let mut search = RangeSearch::new(self.space.coords(color), i32::MAX, self.space, self.metric);
+        let mut best = KNearest::new(k);
+
+        if k > 0 {
+            self.root.find_k_nearest_inner(&mut search, &mut best);
+        }
+
+        best.into_points()
+    }
+
+    fn find_within(&self, color: &ColorPoint, radius_sq: i32) -> Vec<Point> {
+        let search = RangeSearch::new(self.space.coords(color), radius_sq, self.space, self.metric);
+        let mut found = Vec::new();
+        self.root.find_within_inner(&search, &mut found);
+        found
+    }
+
+    fn count_within(&self, color: &ColorPoint, radius_sq: i32) -> usize {
+        let search = RangeSearch::new(self.space.coords(color), radius_sq, self.space, self.metric);
+        self.root.count_within_inner(&search)
+    }
+
     fn is_empty(&self) -> bool {
-        self.len() == 0
+        self.root.is_empty()
     }
 }
 
@@ -339,13 +718,13 @@ fn test_octree_leafy_add_remove() {
     assert!(tree.is_empty());
 
     let point = Point::new(SpacePoint::new(0, 0), ColorPoint::new(0, 0, 0));
-    tree.add(point.clone(), &mut spare_vectors);
+    tree.add(point, &mut spare_vectors);
     assert!(!tree.is_empty());
 
     assert!(tree.has_point(&point));
     assert!(tree.has(point.space()));
 
-    tree.remove(point.clone(), &mut spare_vectors);
+    tree.remove(point, &mut spare_vectors);
     assert!(tree.is_empty());
 
     assert!(!tree.has_point(&point));
@@ -356,7 +735,7 @@ fn test_octree_leafy_add_remove() {
 fn test_octree_init_bounds() {
     let tree = OctreeLeafy::init_tree(2);
     
-    let OctreeLeafy::Node { bounds, .. } = &tree else { panic!("Root should be node") };
+    let OctreeNode::Node { bounds, .. } = &tree.root else { panic!("Root should be node") };
 
     assert_eq!(bounds, &BoundingBox::new(0, 0, 0, 255, 255, 255));
 
@@ -372,11 +751,11 @@ fn test_octree_init_bounds() {
     ];
     
     for color in colors_to_check.iter() {
-        let child = tree.child_for(color);
-        let Some(OctreeLeafy::Node { bounds, .. }) = child
+        let child = tree.root.child_for(&ColorSpace::Rgb.coords(color));
+        let Some(OctreeNode::Node { bounds, .. }) = child
             else { panic!("Child should be node") };
         
-        assert!(bounds.contains_color(&color), "Child node for color {color:?} should contain it, but bounds are {bounds:?}");
+        assert!(bounds.contains_color(color), "Child node for color {color:?} should contain it, but bounds are {bounds:?}");
     }
 }
 
@@ -386,7 +765,7 @@ fn test_octree_find_nearest_single() {
     let mut spare_vectors = Vec::new();
     
     let point = Point::new(SpacePoint::new(0, 0), ColorPoint::new(0, 0, 0));
-    tree.add(point.clone(), &mut spare_vectors);
+    tree.add(point, &mut spare_vectors);
 
     let colors_to_check = [
         ColorPoint::new(0, 0, 0),
@@ -402,7 +781,7 @@ fn test_octree_find_nearest_single() {
     // With only one sample point, all should just find it
     for color in colors_to_check.iter() {
         let nearest = tree.find_nearest(color);
-        assert_eq!(nearest, Some(point.clone()));
+        assert_eq!(nearest, Some(point));
     }
 }
 
@@ -482,3 +861,146 @@ fn test_octree_find_nearest_multi() {
         );
     }
 }
+
+#[test]
+fn test_octree_find_nearest_metrics() {
+    // Random trees searched with each metric should agree with a brute force search
+    let metrics = [
+        ColorMetric::Euclidean,
+        ColorMetric::Manhattan,
+        ColorMetric::Chebyshev,
+        ColorMetric::weighted(3, 4, 2),
+        ColorMetric::weighted(1, 1, 40),
+    ];
+
+    let rng = fastrand::Rng::with_seed(1234);
+    let random_color = || ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..));
+
+    for metric in metrics {
+        let tree = OctreeLeafy::init_tree_with_metric(4, metric);
+        let mut spare_vectors = Vec::new();
+
+        let placed_points = (0..200).map(|_| random_color()).collect::<Vec<_>>();
+        for (i, color) in placed_points.iter().enumerate() {
+            tree.add(Point::new(SpacePoint::new(i as u32, 0), *color), &mut spare_vectors);
+        }
+
+        for _ in 0..200 {
+            let search_color = random_color();
+            let control_dist = placed_points.iter()
+                .map(|color| metric.distance(color, &search_color))
+                .min()
+                .unwrap();
+
+            let nearest = tree.find_nearest(&search_color).expect("Nearest should be found");
+            let nearest_dist = metric.distance(nearest.color(), &search_color);
+
+            assert_eq!(
+                nearest_dist, control_dist,
+                "{metric:?} search for {search_color:?} found {:?} at {nearest_dist} but the nearest is at {control_dist}",
+                nearest.color(),
+            );
+        }
+    }
+}
+
+#[test]
+fn test_octree_find_nearest_perceptual() {
+    // Same as above, but with the tree indexing perceptual coords
+    let rng = fastrand::Rng::with_seed(4321);
+    let random_color = || ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..));
+
+    for space in [ColorSpace::OkLab, ColorSpace::CieLab, ColorSpace::Hsv, ColorSpace::Hsl] {
+        let metric = ColorMetric::Euclidean;
+        let tree = OctreeLeafy::init_tree_in(4, space, metric);
+        let mut spare_vectors = Vec::new();
+
+        let placed_points = (0..200).map(|_| random_color()).collect::<Vec<_>>();
+        for (i, color) in placed_points.iter().enumerate() {
+            let point = Point::new(SpacePoint::new(i as u32, 0), *color);
+            tree.add(point, &mut spare_vectors);
+            assert!(tree.has_point(&point));
+        }
+
+        for _ in 0..200 {
+            let search_color = random_color();
+            let search_coords = space.coords(&search_color);
+            let control_dist = placed_points.iter()
+                .map(|color| space.distance(&metric, &space.coords(color), &search_coords))
+                .min()
+                .unwrap();
+
+            let nearest = tree.find_nearest(&search_color).expect("Nearest should be found");
+            let nearest_dist = space.distance(&metric, &space.coords(nearest.color()), &search_coords);
+
+            assert_eq!(nearest_dist, control_dist, "{space:?} search for {search_color:?} found {:?}", nearest.color());
+        }
+    }
+}
+
+#[test]
+fn test_octree_find_nearest_wraps_hue() {
+    let tree = OctreeLeafy::init_tree_in(4, ColorSpace::Hsv, ColorMetric::Euclidean);
+    let mut spare_vectors = Vec::new();
+
+    // Just past red going backwards (hue 701) vs an orange further off the other way (hue 75)
+    let magenta_red = Point::new(SpacePoint::new(0, 0), ColorPoint::new(255, 0, 40));
+    let orange = Point::new(SpacePoint::new(1, 0), ColorPoint::new(255, 160, 0));
+    tree.add(magenta_red, &mut spare_vectors);
+    tree.add(orange, &mut spare_vectors);
+
+    // Just past red going forwards (hue 19) is only 38 away from the first across the wrap
+    assert_eq!(tree.find_nearest(&ColorPoint::new(255, 40, 0)), Some(magenta_red));
+    assert_eq!(tree.find_nearest(&ColorPoint::new(255, 0, 0)), Some(magenta_red));
+    assert_eq!(tree.find_nearest(&ColorPoint::new(255, 140, 0)), Some(orange));
+}
+
+#[test]
+fn test_octree_tie_breaks() {
+    // The same color waiting at three spaces, like the neighbors of a freshly painted pixel
+    let same = [0, 1, 2].map(|x| Point::new(SpacePoint::new(x, 0), ColorPoint::new(60, 70, 80)));
+    // And two colors the same distance either side of the middle, so in different leaves, newer first in traversal order
+    let upper = Point::new(SpacePoint::new(3, 0), ColorPoint::new(138, 128, 128));
+    let lower = Point::new(SpacePoint::new(4, 0), ColorPoint::new(118, 128, 128));
+
+    let tree_with = |tie_break| {
+        let mut tree = OctreeLeafy::init_tree(4);
+        let mut spare_vectors = Vec::new();
+        tree.set_tie_break(tie_break);
+
+        for point in same.iter().chain([&upper, &lower]) {
+            tree.add(*point, &mut spare_vectors);
+        }
+
+        tree
+    };
+
+    let middle = ColorPoint::new(128, 128, 128);
+
+    let oldest = tree_with(TieBreak::Oldest);
+    assert_eq!(oldest.find_nearest(same[0].color()), Some(same[0]));
+    assert_eq!(oldest.find_nearest(&middle), Some(upper));
+
+    let newest = tree_with(TieBreak::Newest);
+    assert_eq!(newest.find_nearest(same[0].color()), Some(same[2]));
+    assert_eq!(newest.find_nearest(&middle), Some(lower));
+
+    let first_found = tree_with(TieBreak::FirstFound);
+    assert_eq!(first_found.find_nearest(same[0].color()), Some(same[0]));
+
+    // Random picks each of them for some seed, and the same seed picks the same way every time
+    let mut random = tree_with(TieBreak::Random { seed: 0 });
+    let picks = (0..300).map(|seed| {
+        random.set_tie_break(TieBreak::Random { seed });
+        let pick = random.find_nearest(same[0].color()).unwrap();
+        assert_eq!(random.find_nearest(same[0].color()), Some(pick), "The same seed should pick the same way");
+        pick
+    }).collect::<Vec<_>>();
+
+    for point in same {
+        assert!(picks.contains(&point), "Random ties should sometimes pick {point}");
+    }
+
+    let again = tree_with(TieBreak::Random { seed: 25 });
+    assert_eq!(again.find_nearest(same[0].color()), Some(picks[25]), "A new tree with the same seed should pick the same way");
+}
@@ -0,0 +1,158 @@
+use crate::points::ColorPoint;
+
+/// The number of distinct 24-bit colors
+pub const FULL_PALETTE_SIZE: usize = 1 << 24;
+
+/// Every 24-bit color exactly once
+pub fn full_palette() -> Vec<ColorPoint> {
+    lattice_palette(256, 256, 256)
+}
+
+/// Every color on an evenly spaced lattice with the given number of levels per channel
+/// e.g. 32 levels on each channel is the classic 5-5-5 bit cube
+pub fn lattice_palette(r_levels: usize, g_levels: usize, b_levels: usize) -> Vec<ColorPoint> {
+    let r_values = channel_levels(r_levels);
+    let g_values = channel_levels(g_levels);
+    let b_values = channel_levels(b_levels);
+
+    let mut colors = Vec::with_capacity(r_levels * g_levels * b_levels);
+
+    for &r in &r_values {
+        for &g in &g_values {
+            for &b in &b_values {
+                colors.push(ColorPoint::new(r, g, b));
+            }
+        }
+    }
+
+    colors
+}
+
+/// Exactly `count` distinct colors spread as evenly as we can manage over the RGB cube
+pub fn palette_of_size(count: usize) -> Vec<ColorPoint> {
+    assert!(count > 0, "Tried to make an empty palette");
+    assert!(count <= FULL_PALETTE_SIZE, "Tried to make a palette of {count} colors (must be <= {FULL_PALETTE_SIZE})");
+
+    let (r_levels, g_levels, b_levels) = levels_for(count);
+    let lattice = lattice_palette(r_levels, g_levels, b_levels);
+
+    if lattice.len() == count {
+        return lattice;
+    }
+
+    // Our lattice is a bit too big, so step through it evenly
+    // NB the lattice is under 2^24, so these products can't overflow
+    (0..count)
+        .map(|i| lattice[i * lattice.len() / count])
+        .collect()
+}
+
+/// Each of the colors `multiplicity` times over, with the copies next to each other
+/// For canvases bigger than the 2^24 colors we have, so every color still gets used evenly
+pub fn repeated_palette(colors: Vec<ColorPoint>, multiplicity: usize) -> Vec<ColorPoint> {
+    assert!(multiplicity > 0, "Tried to repeat a palette 0 times");
+
+    if multiplicity == 1 {
+        return colors;
+    }
+
+    colors.iter()
+        .flat_map(|&color| std::iter::repeat_n(color, multiplicity))
+        .collect()
+}
+
+/// The smallest-ish lattice that has at least `count` entries
+fn levels_for(count: usize) -> (usize, usize, usize) {
+    // Start with a cube that is big enough...
+    let mut side = 1;
+    while side * side * side < count { side += 1; }
+
+    // ...then trim off blue and red planes while we still fit
+    let (mut r_levels, g_levels, mut b_levels) = (side, side, side);
+    while b_levels > 1 && r_levels * g_levels * (b_levels - 1) >= count { b_levels -= 1; }
+    while r_levels > 1 && (r_levels - 1) * g_levels * b_levels >= count { r_levels -= 1; }
+
+    (r_levels, g_levels, b_levels)
+}
+
+/// The channel values for `levels` evenly spaced steps from 0 to 255
+fn channel_levels(levels: usize) -> Vec<u8> {
+    assert!(levels > 0 && levels <= 256, "Tried to make a channel with {levels} levels (must be in 1..=256)");
+
+    if levels == 1 {
+        // Just take the middle
+        return vec![128];
+    }
+
+    let steps = levels - 1;
+    (0..levels)
+        .map(|i| ((i * 255 + steps / 2) / steps) as u8)
+        .collect()
+}
+
+#[test]
+fn test_full_palette() {
+    let colors = full_palette();
+    assert_eq!(colors.len(), FULL_PALETTE_SIZE);
+
+    // Blue varies fastest
+    assert_eq!(colors[0], ColorPoint::new(0, 0, 0));
+    assert_eq!(colors[1], ColorPoint::new(0, 0, 1));
+    assert_eq!(colors[FULL_PALETTE_SIZE - 1], ColorPoint::new(255, 255, 255));
+}
+
+#[test]
+fn test_lattice_palette_555() {
+    let colors = lattice_palette(32, 32, 32);
+    assert_eq!(colors.len(), 1 << 15);
+
+    let mut sorted = colors.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(sorted.len(), 1 << 15, "Lattice colors should be distinct");
+
+    // We should span the whole range on each channel
+    assert!(colors.contains(&ColorPoint::new(0, 0, 0)));
+    assert!(colors.contains(&ColorPoint::new(255, 255, 255)));
+    assert!(colors.contains(&ColorPoint::new(255, 0, 255)));
+}
+
+#[test]
+fn test_palette_of_size() {
+    for count in [1, 2, 7, 1 << 15, 256 * 128, 1 << 18, 300 * 120, 1000 * 1000] {
+        let colors = palette_of_size(count);
+        assert_eq!(colors.len(), count, "Palette should have exactly {count} colors");
+
+        let mut sorted = colors.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), count, "Palette of {count} should have distinct colors");
+    }
+}
+
+#[test]
+fn test_repeated_palette() {
+    let colors = repeated_palette(palette_of_size(100), 3);
+    assert_eq!(colors.len(), 300);
+    assert_eq!(colors[0], colors[2]);
+    assert_ne!(colors[2], colors[3]);
+
+    let mut sorted = colors.clone();
+    sorted.sort();
+    sorted.dedup();
+    assert_eq!(sorted.len(), 100);
+}
+
+#[test]
+fn test_palette_of_size_is_even() {
+    // 2^18 is an exact 64^3 cube, so every channel value should show up equally often
+    let colors = palette_of_size(1 << 18);
+    let mut red_counts = [0usize; 256];
+    for color in &colors {
+        red_counts[color.r as usize] += 1;
+    }
+
+    let used = red_counts.iter().filter(|&&c| c > 0).collect::<Vec<_>>();
+    assert_eq!(used.len(), 64);
+    assert!(used.iter().all(|&&c| c == 64 * 64));
+}
@@ -1,5 +1,7 @@
 use std::fmt::{self, Debug};
 
+use crate::canvas::{Canvas, MAX_DIMENSION};
+
 // #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 // pub struct SpacePoint {
 //     pub x: u32,
@@ -14,31 +16,24 @@ impl SpacePoint {
     pub fn zero() -> SpacePoint { SpacePoint(0) }
     
     pub fn new(x: u32, y: u32) -> SpacePoint {
-        SpacePoint(y << 12 | x)
+        debug_assert!(x < MAX_DIMENSION && y < MAX_DIMENSION, "Tried to pack {x},{y} into a SpacePoint");
+        SpacePoint(y << 16 | x)
     }
 
     pub fn xy(&self) -> (u32, u32) {
-        let x = self.0 & 4095;
-        let y = (self.0 >> 12) & 4095;
+        let x = self.0 & 0xFFFF;
+        let y = (self.0 >> 16) & 0xFFFF;
         (x, y)
     }
 
-    pub fn get_neighbors(&self, ret: &mut Vec<SpacePoint>)  {
-        // TODO we should keep this around and reuse it instead of alloc'ing it
+    pub fn get_neighbors(&self, canvas: &Canvas, ret: &mut Vec<SpacePoint>)  {
         let (x, y) = self.xy();
         ret.clear();
 
-        if x > 0    { ret.push(SpacePoint::new(x - 1, y    )); }
-        if x < 4095 { ret.push(SpacePoint::new(x + 1, y    )); }
-        if y > 0    { ret.push(SpacePoint::new(x,     y - 1)); }
-        if y < 4095 { ret.push(SpacePoint::new(x,     y + 1)); }
-
-        //ret
-    }
-
-    pub fn offset(&self) -> usize {
-        let (x, y) = self.xy();
-        usize::try_from(y << 12 | x).expect("Should not index a point beyond 2^24")
+        if x > 0                 { ret.push(SpacePoint::new(x - 1, y    )); }
+        if x + 1 < canvas.width  { ret.push(SpacePoint::new(x + 1, y    )); }
+        if y > 0                 { ret.push(SpacePoint::new(x,     y - 1)); }
+        if y + 1 < canvas.height { ret.push(SpacePoint::new(x,     y + 1)); }
     }
 }
 
@@ -0,0 +1,148 @@
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::TAU;
+
+use fastrand::Rng;
+
+use crate::canvas::Canvas;
+
+/// Some preset arrangements of seed pixels
+#[derive(Clone, Debug, PartialEq)]
+pub enum SeedLayout {
+    /// This many distinct random positions (or the whole canvas if that's fewer)
+    Random { count: usize },
+    /// Evenly spaced cells across the canvas, with a seed at the center of each
+    Grid { columns: u32, rows: u32 },
+    /// This many seeds evenly spaced from one point to another, inclusive
+    Line { from: (u32, u32), to: (u32, u32), count: usize },
+    /// This many seeds evenly spaced around a circle, any that fall off the canvas are dropped
+    Circle { center: (u32, u32), radius: u32, count: usize },
+    /// Every pixel around the edge of the canvas
+    Border,
+    /// Exactly these positions, any outside the canvas are dropped
+    Points(Vec<(u32, u32)>),
+}
+
+impl SeedLayout {
+    /// The positions for this layout on the given canvas, without duplicates
+    pub fn positions(&self, canvas: &Canvas, rng: &Rng) -> Vec<(u32, u32)> {
+        let (width, height) = (canvas.width, canvas.height);
+
+        let candidates: Vec<(i64, i64)> = match self {
+            SeedLayout::Random { count } => {
+                // The first `count` steps of a Fisher-Yates shuffle of every offset, so even picking
+                // the whole canvas takes `count` draws
+                // NB only the swapped offsets are stored, as the canvas can be far bigger than the count
+                let count = (*count).min(canvas.size());
+                let mut swapped: HashMap<usize, usize> = HashMap::with_capacity(2 * count);
+
+                (0..count)
+                    .map(|i| {
+                        let j = rng.usize(i..canvas.size());
+                        let picked = swapped.get(&j).copied().unwrap_or(j);
+                        swapped.insert(j, swapped.get(&i).copied().unwrap_or(i));
+
+                        let (x, y) = canvas.space_at(picked).xy();
+                        (x as i64, y as i64)
+                    })
+                    .collect()
+            }
+            SeedLayout::Grid { columns, rows } => {
+                assert!(*columns > 0 && *rows > 0, "Tried to make a {columns}x{rows} seed grid");
+
+                (0..*rows)
+                    .flat_map(|row| (0..*columns).map(move |column| (column, row)))
+                    .map(|(column, row)| (
+                        ((2 * column + 1) as u64 * width as u64 / (2 * *columns) as u64) as i64,
+                        ((2 * row + 1) as u64 * height as u64 / (2 * *rows) as u64) as i64,
+                    ))
+                    .collect()
+            }
+            SeedLayout::Line { from, to, count } => {
+                let steps = count.saturating_sub(1).max(1) as f64;
+
+                (0..*count)
+                    .map(|i| {
+                        let t = i as f64 / steps;
+                        (
+                            (from.0 as f64 + t * (to.0 as f64 - from.0 as f64)).round() as i64,
+                            (from.1 as f64 + t * (to.1 as f64 - from.1 as f64)).round() as i64,
+                        )
+                    })
+                    .collect()
+            }
+            SeedLayout::Circle { center, radius, count } => {
+                (0..*count)
+                    .map(|i| {
+                        let angle = TAU * i as f64 / *count as f64;
+                        (
+                            (center.0 as f64 + *radius as f64 * angle.cos()).round() as i64,
+                            (center.1 as f64 + *radius as f64 * angle.sin()).round() as i64,
+                        )
+                    })
+                    .collect()
+            }
+            SeedLayout::Border => {
+                let (w, h) = (width as i64, height as i64);
+
+                // Clockwise from the top left, the dedup below takes care of the corners
+                let top = (0..w).map(|x| (x, 0));
+                let right = (0..h).map(|y| (w - 1, y));
+                let bottom = (0..w).rev().map(|x| (x, h - 1));
+                let left = (0..h).rev().map(|y| (0, y));
+
+                top.chain(right).chain(bottom).chain(left).collect()
+            }
+            SeedLayout::Points(points) => {
+                points.iter().map(|&(x, y)| (x as i64, y as i64)).collect()
+            }
+        };
+
+        // Keep the first of any duplicates so the layout order is stable
+        let mut seen = HashSet::with_capacity(candidates.len());
+        candidates.into_iter()
+            .filter(|&(x, y)| x >= 0 && y >= 0 && canvas.contains(x as u32, y as u32))
+            .map(|(x, y)| (x as u32, y as u32))
+            .filter(|position| seen.insert(*position))
+            .collect()
+    }
+}
+
+#[test]
+fn test_seed_layouts() {
+    let canvas = Canvas::new(100, 50);
+    let rng = Rng::with_seed(3);
+
+    assert_eq!(SeedLayout::Grid { columns: 2, rows: 2 }.positions(&canvas, &rng), [(25, 12), (75, 12), (25, 37), (75, 37)]);
+    assert_eq!(
+        SeedLayout::Line { from: (0, 0), to: (99, 49), count: 3 }.positions(&canvas, &rng),
+        [(0, 0), (50, 25), (99, 49)],
+    );
+    assert_eq!(
+        SeedLayout::Circle { center: (10, 10), radius: 20, count: 4 }.positions(&canvas, &rng),
+        [(30, 10), (10, 30)],
+        "Off canvas points should be dropped",
+    );
+    assert_eq!(
+        SeedLayout::Points(vec![(1, 2), (3, 4), (1, 2), (100, 0)]).positions(&canvas, &rng),
+        [(1, 2), (3, 4)],
+        "Duplicates and off canvas points should be dropped",
+    );
+
+    let border = SeedLayout::Border.positions(&canvas, &rng);
+    assert_eq!(border.len(), 2 * 100 + 2 * 50 - 4);
+    assert!(border.iter().all(|&(x, y)| x == 0 || y == 0 || x == 99 || y == 49));
+}
+
+#[test]
+fn test_random_seed_layout_is_distinct() {
+    let canvas = Canvas::new(8, 8);
+    let rng = Rng::with_seed(4);
+
+    let positions = SeedLayout::Random { count: 40 }.positions(&canvas, &rng);
+    assert_eq!(positions.len(), 40);
+    assert_eq!(positions.iter().collect::<HashSet<_>>().len(), 40);
+
+    // Asking for more than there is just gives us everything
+    let everything = SeedLayout::Random { count: 1000 }.positions(&canvas, &rng);
+    assert_eq!(everything.iter().collect::<HashSet<_>>().len(), 64);
+}
@@ -0,0 +1,102 @@
+use std::{error, fmt, path::Path};
+
+use crate::{canvas::Canvas, color_generator::SeedError, image::read_png, points::ColorPoint};
+
+/// Seeds read out of an image, where every non-transparent pixel is a seed of that color
+pub struct SeedMap {
+    canvas: Canvas,
+    seeds: Vec<(u32, u32, ColorPoint)>,
+}
+
+/// Why we couldn't use a seed map
+#[derive(Debug)]
+pub enum SeedMapError {
+    Decoding(png::DecodingError),
+    SizeMismatch { expected: Canvas, found: Canvas },
+    Seed(SeedError),
+}
+
+impl SeedMap {
+    pub fn read(path: &Path) -> Result<SeedMap, SeedMapError> {
+        let (canvas, pixels) = read_png(path).map_err(SeedMapError::Decoding)?;
+
+        let seeds = pixels.iter()
+            .enumerate()
+            .filter(|(_, [_, _, _, a])| *a != 0)
+            .map(|(offset, &[r, g, b, _])| {
+                let (x, y) = canvas.space_at(offset).xy();
+                (x, y, ColorPoint::new(r, g, b))
+            })
+            .collect();
+
+        Ok(SeedMap { canvas, seeds })
+    }
+
+    /// The size of the image we were read from, which has to match the generator's canvas
+    pub fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
+
+    /// Every seed as x, y and color, in row order
+    pub fn seeds(&self) -> &[(u32, u32, ColorPoint)] {
+        &self.seeds
+    }
+}
+
+impl fmt::Display for SeedMapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeedMapError::Decoding(err) => write!(f, "Could not read seed map: {err}"),
+            SeedMapError::SizeMismatch { expected, found } => write!(f, "Seed map is {found} but we need {expected}"),
+            SeedMapError::Seed(err) => write!(f, "Could not place seed: {err}"),
+        }
+    }
+}
+
+impl error::Error for SeedMapError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SeedMapError::Decoding(err) => Some(err),
+            SeedMapError::SizeMismatch { .. } => None,
+            SeedMapError::Seed(err) => Some(err),
+        }
+    }
+}
+
+impl From<SeedError> for SeedMapError {
+    fn from(err: SeedError) -> Self {
+        SeedMapError::Seed(err)
+    }
+}
+
+/// Writes out a little RGBA image for tests
+#[cfg(test)]
+pub(crate) fn write_test_png(name: &str, width: u32, height: u32, pixels: &[[u8; 4]]) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("rust_colors_{}_{name}.png", std::process::id()));
+
+    let file = std::fs::File::create(&path).unwrap();
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().unwrap().write_image_data(pixels.concat().as_ref()).unwrap();
+
+    path
+}
+
+#[test]
+fn test_read_seed_map() {
+    let clear = [0, 0, 0, 0];
+    let path = write_test_png("seed_map", 3, 2, &[
+        clear, [255, 0, 0, 255], clear,
+        [0, 0, 255, 128], clear, clear,
+    ]);
+
+    let map = SeedMap::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(map.canvas(), &Canvas::new(3, 2));
+    assert_eq!(map.seeds(), [(1, 0, ColorPoint::new(255, 0, 0)), (0, 1, ColorPoint::new(0, 0, 255))]);
+
+    let missing = SeedMap::read(Path::new("/definitely/not/here.png"));
+    assert!(matches!(missing, Err(SeedMapError::Decoding(_))));
+}
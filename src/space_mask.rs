@@ -0,0 +1,67 @@
+use bitvec::prelude::*;
+use fnv::FnvHashSet;
+
+use crate::{canvas::Canvas, points::SpacePoint};
+
+/// A set of spaces, e.g. the ones we've claimed for writing
+/// Dense masks have a bit for every pixel of the canvas, sparse ones only remember what's set,
+/// which is what lets us work on canvases far too big to allocate
+pub enum SpaceMask {
+    Dense {
+        canvas: Canvas,
+        bits: BitVec<usize, Msb0>,
+    },
+    Sparse(FnvHashSet<SpacePoint>),
+}
+
+impl SpaceMask {
+    pub fn dense(canvas: Canvas) -> SpaceMask {
+        SpaceMask::Dense { canvas, bits: bitvec![usize, Msb0; 0; canvas.size()] }
+    }
+
+    pub fn sparse() -> SpaceMask {
+        SpaceMask::Sparse(FnvHashSet::default())
+    }
+
+    #[inline]
+    pub fn test(&self, space: &SpacePoint) -> bool {
+        match self {
+            SpaceMask::Dense { canvas, bits } => bits[canvas.space_offset(space)],
+            SpaceMask::Sparse(set) => set.contains(space),
+        }
+    }
+
+    #[inline]
+    pub fn set(&mut self, space: &SpacePoint, value: bool) {
+        match self {
+            SpaceMask::Dense { canvas, bits } => bits.set(canvas.space_offset(space), value),
+            SpaceMask::Sparse(set) => {
+                if value { set.insert(*space); } else { set.remove(space); }
+            }
+        }
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        matches!(self, SpaceMask::Sparse(_))
+    }
+}
+
+#[test]
+fn test_space_masks_agree() {
+    let canvas = Canvas::new(300, 200);
+    let mut dense = SpaceMask::dense(canvas);
+    let mut sparse = SpaceMask::sparse();
+    let rng = fastrand::Rng::with_seed(10);
+
+    for _ in 0..2000 {
+        let space = SpacePoint::new(rng.u32(..300), rng.u32(..200));
+        let value = rng.bool();
+        dense.set(&space, value);
+        sparse.set(&space, value);
+    }
+
+    for offset in 0..canvas.size() {
+        let space = canvas.space_at(offset);
+        assert_eq!(dense.test(&space), sparse.test(&space), "Masks disagree at {space}");
+    }
+}
@@ -0,0 +1,293 @@
+use crate::{canvas::Canvas, points::SpacePoint};
+
+/// How the pixels of a canvas connect to each other, i.e. where the frontier can spread to
+pub trait Topology: Send + Sync {
+    /// Fills `ret` with every space next to `space`, without duplicates or `space` itself
+    fn neighbors(&self, space: &SpacePoint, canvas: &Canvas, ret: &mut Vec<SpacePoint>);
+}
+
+/// Which canvas edges join back up with the opposite edge
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum Wrap {
+    #[default]
+    None,
+    Horizontal,
+    Vertical,
+    /// A torus, so the output tiles in every direction
+    Both,
+}
+
+/// Which nearby pixels on a grid count as neighbors
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub enum Neighborhood {
+    /// The 4 orthogonal pixels
+    #[default]
+    VonNeumann,
+    /// The 8 orthogonal and diagonal pixels, like OCTNEIGH in the C++ version
+    Moore,
+    /// The 12 pixels within a distance of 2, like OCTNEIGH and FARNEIGH together
+    Radius2,
+    /// Any x, y offsets, which need not be symmetric
+    Stencil(Vec<(i32, i32)>),
+}
+
+/// The usual square pixel grid
+#[derive(Clone, Debug, Default, Hash, Eq, PartialEq)]
+pub struct GridTopology {
+    wrap: Wrap,
+    neighborhood: Neighborhood,
+}
+
+/// A grid of pointy-topped hexagons, where every cell has 6 equally distant neighbors
+/// Cells use "odd-r" offset coordinates in the usual SpacePoint, i.e. x is the column and
+/// y the row, with odd rows sitting half a cell to the right. This means the canvas,
+/// masks and so on all work as usual, but the image needs a `rasterize` to look right
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub struct HexTopology;
+
+const VON_NEUMANN: [(i32, i32); 4] = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+const MOORE: [(i32, i32); 8] = [(-1, 0), (1, 0), (0, -1), (0, 1), (-1, -1), (1, -1), (-1, 1), (1, 1)];
+const RADIUS_2: [(i32, i32); 12] = [
+    (-1, 0), (1, 0), (0, -1), (0, 1),
+    (-1, -1), (1, -1), (-1, 1), (1, 1),
+    (-2, 0), (2, 0), (0, -2), (0, 2),
+];
+
+impl Neighborhood {
+    /// A custom neighborhood, duplicates are dropped
+    pub fn stencil(offsets: &[(i32, i32)]) -> Neighborhood {
+        assert!(!offsets.contains(&(0, 0)), "Tried to make a stencil that includes its own center");
+
+        let mut deduped = Vec::with_capacity(offsets.len());
+        for offset in offsets {
+            if !deduped.contains(offset) {
+                deduped.push(*offset);
+            }
+        }
+        assert!(!deduped.is_empty(), "Tried to make an empty stencil");
+
+        Neighborhood::Stencil(deduped)
+    }
+
+    pub fn offsets(&self) -> &[(i32, i32)] {
+        match self {
+            Neighborhood::VonNeumann => &VON_NEUMANN,
+            Neighborhood::Moore => &MOORE,
+            Neighborhood::Radius2 => &RADIUS_2,
+            Neighborhood::Stencil(offsets) => offsets,
+        }
+    }
+}
+
+impl Wrap {
+    pub fn wraps_x(&self) -> bool {
+        matches!(self, Wrap::Horizontal | Wrap::Both)
+    }
+
+    pub fn wraps_y(&self) -> bool {
+        matches!(self, Wrap::Vertical | Wrap::Both)
+    }
+}
+
+impl GridTopology {
+    pub fn new(wrap: Wrap) -> GridTopology {
+        Self::with_neighborhood(wrap, Neighborhood::default())
+    }
+
+    pub fn with_neighborhood(wrap: Wrap, neighborhood: Neighborhood) -> GridTopology {
+        GridTopology { wrap, neighborhood }
+    }
+
+    pub fn wrap(&self) -> Wrap {
+        self.wrap
+    }
+
+    pub fn neighborhood(&self) -> &Neighborhood {
+        &self.neighborhood
+    }
+
+    /// Where we land after moving by dx, dy from x, y, if that's still on the canvas
+    fn step(&self, canvas: &Canvas, (x, y): (u32, u32), (dx, dy): (i32, i32)) -> Option<SpacePoint> {
+        let x = Self::step_axis(x, dx, canvas.width, self.wrap.wraps_x())?;
+        let y = Self::step_axis(y, dy, canvas.height, self.wrap.wraps_y())?;
+
+        Some(SpacePoint::new(x, y))
+    }
+
+    fn step_axis(at: u32, by: i32, size: u32, wraps: bool) -> Option<u32> {
+        let to = at as i64 + by as i64;
+
+        if wraps {
+            Some(to.rem_euclid(size as i64) as u32)
+        } else if to >= 0 && to < size as i64 {
+            Some(to as u32)
+        } else {
+            None
+        }
+    }
+}
+
+impl Topology for GridTopology {
+    fn neighbors(&self, space: &SpacePoint, canvas: &Canvas, ret: &mut Vec<SpacePoint>) {
+        if self.wrap == Wrap::None && self.neighborhood == Neighborhood::VonNeumann {
+            // Nice and simple
+            space.get_neighbors(canvas, ret);
+            return;
+        }
+
+        let xy = space.xy();
+        ret.clear();
+
+        for &offset in self.neighborhood.offsets() {
+            // NB narrow canvases can wrap back onto us or give the same neighbor twice
+            if let Some(neighbor) = self.step(canvas, xy, offset) {
+                if neighbor != *space && !ret.contains(&neighbor) {
+                    ret.push(neighbor);
+                }
+            }
+        }
+    }
+}
+
+// Same, then up and down to the left and right, in odd-r coords
+const HEX_EVEN_ROW: [(i32, i32); 6] = [(-1, 0), (1, 0), (-1, -1), (0, -1), (-1, 1), (0, 1)];
+const HEX_ODD_ROW: [(i32, i32); 6] = [(-1, 0), (1, 0), (0, -1), (1, -1), (0, 1), (1, 1)];
+
+impl HexTopology {
+    /// Draws RGBA cells (as from Image::to_raw) as a brick wall of 2*scale square pixel bricks
+    /// Each brick touches exactly its 6 hex neighbors, and odd rows are shifted right by half a brick
+    /// Returns the width and height of the result along with its RGBA pixels
+    pub fn rasterize(&self, canvas: &Canvas, raw: &[u8], scale: u32) -> (u32, u32, Vec<u8>) {
+        assert!(scale > 0, "Tried to rasterize hexes at scale 0");
+        assert_eq!(raw.len(), canvas.size() * 4, "Tried to rasterize the wrong number of cells for {}", canvas);
+
+        let brick = 2 * scale as usize;
+        let width = canvas.width as usize * brick + scale as usize;
+        let height = canvas.height as usize * brick;
+
+        // NB the half brick left over at the end of each row stays transparent
+        let mut ret = vec![0u8; width * height * 4];
+        for offset in 0..canvas.size() {
+            let (x, y) = canvas.space_at(offset).xy();
+            let shift = if y % 2 == 1 { scale as usize } else { 0 };
+            let cell = &raw[offset * 4..][..4];
+
+            for py in 0..brick {
+                let row_start = ((y as usize * brick + py) * width + x as usize * brick + shift) * 4;
+                for px in 0..brick {
+                    ret[row_start + px * 4..][..4].copy_from_slice(cell);
+                }
+            }
+        }
+
+        (width as u32, height as u32, ret)
+    }
+}
+
+impl Topology for HexTopology {
+    fn neighbors(&self, space: &SpacePoint, canvas: &Canvas, ret: &mut Vec<SpacePoint>) {
+        let (x, y) = space.xy();
+        let offsets = if y % 2 == 0 { &HEX_EVEN_ROW } else { &HEX_ODD_ROW };
+        ret.clear();
+
+        for (dx, dy) in offsets {
+            let (nx, ny) = (x as i64 + *dx as i64, y as i64 + *dy as i64);
+            if nx >= 0 && ny >= 0 && canvas.contains(nx as u32, ny as u32) {
+                ret.push(SpacePoint::new(nx as u32, ny as u32));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_grid_topology_wraps() {
+    let canvas = Canvas::new(4, 3);
+    let mut neighbors = Vec::new();
+    let corner = SpacePoint::new(0, 0);
+
+    GridTopology::default().neighbors(&corner, &canvas, &mut neighbors);
+    assert_eq!(neighbors, [SpacePoint::new(1, 0), SpacePoint::new(0, 1)]);
+
+    GridTopology::new(Wrap::Horizontal).neighbors(&corner, &canvas, &mut neighbors);
+    assert_eq!(neighbors, [SpacePoint::new(3, 0), SpacePoint::new(1, 0), SpacePoint::new(0, 1)]);
+
+    GridTopology::new(Wrap::Vertical).neighbors(&corner, &canvas, &mut neighbors);
+    assert_eq!(neighbors, [SpacePoint::new(1, 0), SpacePoint::new(0, 2), SpacePoint::new(0, 1)]);
+
+    GridTopology::new(Wrap::Both).neighbors(&SpacePoint::new(3, 2), &canvas, &mut neighbors);
+    assert_eq!(neighbors, [SpacePoint::new(2, 2), SpacePoint::new(0, 2), SpacePoint::new(3, 1), SpacePoint::new(3, 0)]);
+
+    // A two wide canvas reaches the same neighbor both ways
+    GridTopology::new(Wrap::Both).neighbors(&corner, &Canvas::new(2, 1), &mut neighbors);
+    assert_eq!(neighbors, [SpacePoint::new(1, 0)]);
+}
+
+#[test]
+fn test_grid_neighborhoods() {
+    let canvas = Canvas::new(8, 8);
+    let mut neighbors = Vec::new();
+    let center = SpacePoint::new(4, 4);
+
+    let count = |neighborhood: Neighborhood, space: &SpacePoint, neighbors: &mut Vec<SpacePoint>| {
+        GridTopology::with_neighborhood(Wrap::None, neighborhood).neighbors(space, &canvas, neighbors);
+        neighbors.len()
+    };
+
+    assert_eq!(count(Neighborhood::VonNeumann, &center, &mut neighbors), 4);
+    assert_eq!(count(Neighborhood::Moore, &center, &mut neighbors), 8);
+    assert_eq!(count(Neighborhood::Radius2, &center, &mut neighbors), 12);
+    assert!(neighbors.contains(&SpacePoint::new(4, 2)) && neighbors.contains(&SpacePoint::new(3, 3)));
+
+    // Corners lose the ones off the canvas
+    assert_eq!(count(Neighborhood::Moore, &SpacePoint::new(0, 0), &mut neighbors), 3);
+    assert_eq!(count(Neighborhood::Radius2, &SpacePoint::new(0, 0), &mut neighbors), 5);
+
+    // Knight's moves, only going right
+    let knight = Neighborhood::stencil(&[(2, 1), (2, -1), (1, 2), (1, -2), (2, 1)]);
+    assert_eq!(knight.offsets().len(), 4);
+    assert_eq!(count(knight.clone(), &center, &mut neighbors), 4);
+    assert_eq!(neighbors[0], SpacePoint::new(6, 5));
+    assert_eq!(count(knight, &SpacePoint::new(7, 4), &mut neighbors), 0);
+}
+
+#[test]
+fn test_hex_neighbors() {
+    let canvas = Canvas::new(6, 6);
+    let mut neighbors = Vec::new();
+
+    HexTopology.neighbors(&SpacePoint::new(2, 2), &canvas, &mut neighbors);
+    assert_eq!(neighbors.len(), 6);
+    assert!(neighbors.contains(&SpacePoint::new(1, 1)) && !neighbors.contains(&SpacePoint::new(3, 1)));
+
+    HexTopology.neighbors(&SpacePoint::new(2, 3), &canvas, &mut neighbors);
+    assert!(neighbors.contains(&SpacePoint::new(3, 2)) && !neighbors.contains(&SpacePoint::new(1, 2)));
+
+    // Being neighbors should go both ways
+    let mut back = Vec::new();
+    for offset in 0..canvas.size() {
+        let space = canvas.space_at(offset);
+        HexTopology.neighbors(&space, &canvas, &mut neighbors);
+        for neighbor in &neighbors {
+            HexTopology.neighbors(neighbor, &canvas, &mut back);
+            assert!(back.contains(&space), "{neighbor} should neighbor {space}");
+        }
+    }
+}
+
+#[test]
+fn test_hex_rasterize() {
+    // Two rows of two cells, each its own color
+    let canvas = Canvas::new(2, 2);
+    let raw = [1, 1, 1, 255, 2, 2, 2, 255, 3, 3, 3, 255, 4, 4, 4, 255];
+
+    let (width, height, pixels) = HexTopology.rasterize(&canvas, &raw, 1);
+    assert_eq!((width, height), (5, 4));
+
+    let reds = pixels.chunks(4).map(|px| px[0]).collect::<Vec<_>>();
+    assert_eq!(reds, [
+        1, 1, 2, 2, 0,
+        1, 1, 2, 2, 0,
+        0, 3, 3, 4, 4,
+        0, 3, 3, 4, 4,
+    ]);
+}
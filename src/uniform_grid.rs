@@ -0,0 +1,398 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use fnv::FnvHashMap;
+use parking_lot::RwLock;
+
+use crate::{points::{SpacePoint, Point, ColorPoint}, bounding_box::BoundingBox, color_metric::ColorMetric, color_space::{ColorSpace, Coords}, nn_search_3d::NnSearch3d, octree_leafy::{KNearest, LeafEntry, NearestSearch, RangeSearch}};
+
+/// A flat grid of equally sized cells over the color space, searched in shells outward from the target's cell
+/// Like OctreeLeafy each cell has its own lock, so adds, removes and searches can all overlap
+pub struct UniformGrid {
+    cells: Vec<RwLock<Vec<LeafEntry>>>,
+    // Cells along each axis, and how much of the color space each covers
+    cells_per_axis: usize,
+    cell_size: [i32; 3],
+    bounds: BoundingBox,
+    total_points: AtomicUsize,
+    // How many of our points wait at each space, so has doesn't have to look through every cell
+    // NB split up by space like KdTree's shards, and only ever locked after a cell
+    spaces: Vec<RwLock<FnvHashMap<SpacePoint, usize>>>,
+    space: ColorSpace,
+    metric: ColorMetric,
+}
+
+const SPACE_SHARDS: usize = 8;
+
+impl UniformGrid {
+    pub fn new() -> UniformGrid {
+        Self::new_in(32, ColorSpace::default(), ColorMetric::default())
+    }
+
+    /// A grid with cells_per_axis^3 cells that indexes (and searches) points by their coords in the given color space
+    pub fn new_in(cells_per_axis: usize, space: ColorSpace, metric: ColorMetric) -> UniformGrid {
+        assert!(cells_per_axis > 0, "Tried to make a grid with 0 cells per axis");
+
+        let bounds = space.bounds();
+        let extent = [bounds.ur - bounds.lr, bounds.ug - bounds.lg, bounds.ub - bounds.lb];
+        assert!(
+            extent.iter().all(|&e| e + 1 >= cells_per_axis as i32),
+            "Tried to make a grid with {cells_per_axis} cells per axis over {bounds}"
+        );
+
+        UniformGrid {
+            cells: (0..cells_per_axis.pow(3)).map(|_| RwLock::new(Vec::new())).collect(),
+            cells_per_axis,
+            cell_size: extent.map(|e| (e + cells_per_axis as i32) / cells_per_axis as i32),
+            bounds,
+            total_points: AtomicUsize::new(0),
+            spaces: (0..SPACE_SHARDS).map(|_| RwLock::new(FnvHashMap::default())).collect(),
+            space,
+            metric,
+        }
+    }
+
+    pub fn metric(&self) -> &ColorMetric {
+        &self.metric
+    }
+
+    pub fn color_space(&self) -> &ColorSpace {
+        &self.space
+    }
+
+    fn lower(&self) -> Coords {
+        [self.bounds.lr, self.bounds.lg, self.bounds.lb]
+    }
+
+    fn spaces(&self, space: &SpacePoint) -> &RwLock<FnvHashMap<SpacePoint, usize>> {
+        let (x, y) = space.xy();
+        &self.spaces[(x as usize + 3 * y as usize) % SPACE_SHARDS]
+    }
+
+    /// Which cell along each axis the coords fall in
+    fn cell_for(&self, coords: &Coords) -> [usize; 3] {
+        let lower = self.lower();
+        [0, 1, 2].map(|axis| {
+            let idx = (coords[axis] - lower[axis]) / self.cell_size[axis];
+            idx.clamp(0, self.cells_per_axis as i32 - 1) as usize
+        })
+    }
+
+    fn cell(&self, [i, j, k]: [usize; 3]) -> &RwLock<Vec<LeafEntry>> {
+        &self.cells[(i * self.cells_per_axis + j) * self.cells_per_axis + k]
+    }
+
+    /// The coords a cell covers
+    fn cell_bounds(&self, cell: [usize; 3]) -> BoundingBox {
+        let lower = self.lower();
+        let [l0, l1, l2] = [0, 1, 2].map(|axis| lower[axis] + cell[axis] as i32 * self.cell_size[axis]);
+        let [u0, u1, u2] = [0, 1, 2].map(|axis| lower[axis] + (cell[axis] as i32 + 1) * self.cell_size[axis] - 1);
+        BoundingBox::new(l0, l1, l2, u0.min(self.bounds.ur), u1.min(self.bounds.ug), u2.min(self.bounds.ub))
+    }
+
+    /// The offsets from the center cell along an axis we ever need to look at
+    /// NB on a wrapping axis every cell is within half the grid either way, so we stop there
+    fn offset_range(&self, axis: usize, center: usize) -> (i32, i32) {
+        let n = self.cells_per_axis as i32;
+        if axis == 0 && self.space.wrap_period().is_some() {
+            (-(n / 2), (n - 1) / 2)
+        } else {
+            (-(center as i32), n - 1 - center as i32)
+        }
+    }
+
+    /// Hands `visit` each cell exactly `ring` cells away (in the Chebyshev sense) from the center
+    fn for_ring_cells(&self, center: [usize; 3], ring: i32, ranges: &[(i32, i32); 3], mut visit: impl FnMut([usize; 3])) {
+        let n = self.cells_per_axis as i32;
+        let clip = |(lo, hi): (i32, i32)| (lo.max(-ring), hi.min(ring));
+        let (r0, r1, r2) = (clip(ranges[0]), clip(ranges[1]), clip(ranges[2]));
+
+        for d0 in r0.0..=r0.1 {
+            for d1 in r1.0..=r1.1 {
+                // Only the faces of the shell, unless we're on an edge of it already
+                let on_shell = d0.abs() == ring || d1.abs() == ring;
+
+                for d2 in (r2.0..=r2.1).filter(|d2| on_shell || d2.abs() == ring) {
+                    visit([
+                        (center[0] as i32 + d0).rem_euclid(n) as usize,
+                        (center[1] as i32 + d1) as usize,
+                        (center[2] as i32 + d2) as usize,
+                    ]);
+                }
+            }
+        }
+    }
+
+    /// Checks the cells exactly `ring` cells away from the center
+    fn search_ring(&self, pt: &Coords, center: [usize; 3], ring: i32, ranges: &[(i32, i32); 3], search: &mut NearestSearch, found: &mut bool) {
+        self.for_ring_cells(center, ring, ranges, |cell| {
+            if *found && !search.overlaps(&self.cell_bounds(cell)) {
+                // Don't bother
+                return;
+            }
+
+            for entry in self.cell(cell).read().iter() {
+                if *found && !search.covers(&entry.coords) {
+                    continue;
+                }
+
+                let dist = search.space.distance(&search.metric, &entry.coords, pt);
+                if !*found || dist < search.nearest_dist {
+                    *found = true;
+                    search.nearest.clone_from(&entry.point);
+                    search.nearest_dist = dist;
+                    search.bounds.set_around_metric(pt, dist, &search.metric);
+                }
+            }
+        });
+    }
+
+    /// Hands `visit` every point in cells that overlap the search, shell by shell until they hold all of it
+    fn visit_range(&self, search: &mut RangeSearch, mut visit: impl FnMut(&LeafEntry, &mut RangeSearch)) {
+        let center = self.cell_for(&search.center);
+        let ranges = [0, 1, 2].map(|axis| self.offset_range(axis, center[axis]));
+        let last_ring = ranges.iter().map(|&(lo, hi)| (-lo).max(hi)).max().unwrap();
+
+        for ring in 0..=last_ring {
+            self.for_ring_cells(center, ring, &ranges, |cell| {
+                if !search.overlaps(&self.cell_bounds(cell)) {
+                    // Don't bother
+                    return;
+                }
+
+                for entry in self.cell(cell).read().iter() {
+                    visit(entry, search);
+                }
+            });
+
+            if self.rings_cover(center, ring, &ranges, &search.bounds) {
+                break;
+            }
+        }
+    }
+
+    /// Whether the shells up to `ring` hold everything inside the search bounds
+    fn rings_cover(&self, center: [usize; 3], ring: i32, ranges: &[(i32, i32); 3], bounds: &BoundingBox) -> bool {
+        let lower = self.lower();
+        let search_bounds = [
+            (bounds.lr, bounds.ur),
+            (bounds.lg, bounds.ug),
+            (bounds.lb, bounds.ub),
+        ];
+
+        (0..3).all(|axis| {
+            let (lo, hi) = ranges[axis];
+            let (search_lo, search_hi) = search_bounds[axis];
+
+            if let (0, Some(period)) = (axis, self.space.wrap_period()) {
+                return self.rings_cover_wrapping(center[0], ring, (lo, hi), (search_lo, search_hi), period);
+            }
+
+            // NB past the last cell there's nothing to miss
+            let covers_lo = ring >= -lo || search_lo >= lower[axis] + (center[axis] as i32 - ring) * self.cell_size[axis];
+            let covers_hi = ring >= hi || search_hi < lower[axis] + (center[axis] as i32 + ring + 1) * self.cell_size[axis];
+            covers_lo && covers_hi
+        })
+    }
+
+    /// Like rings_cover, along a wrapping first axis
+    /// NB the cells don't tile the period exactly, so the search bounds have to be wrapped back into it before finding their cells
+    fn rings_cover_wrapping(&self, center: usize, ring: i32, (lo, hi): (i32, i32), (search_lo, search_hi): (i32, i32), period: i32) -> bool {
+        if ring >= -lo && ring >= hi {
+            // We've been everywhere
+            return true;
+        }
+
+        if search_hi - search_lo + 1 >= period {
+            // The search goes all the way around
+            return false;
+        }
+
+        // The search bounds hold the center, so they reach this many cells back and forward from it
+        let n = self.cells_per_axis as i32;
+        let cell_of = |value: i32| (value.rem_euclid(period) - self.bounds.lr) / self.cell_size[0];
+        let back = (center as i32 - cell_of(search_lo)).rem_euclid(n);
+        let forward = (cell_of(search_hi) - center as i32).rem_euclid(n);
+
+        back <= ring && forward <= ring
+    }
+}
+
+impl Default for UniformGrid {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NnSearch3d for UniformGrid {
+    fn has(&self, pt: &SpacePoint) -> bool {
+        self.spaces(pt).read().contains_key(pt)
+    }
+
+    fn has_point(&self, pt: &Point) -> bool {
+        let coords = self.space.coords(pt.color());
+        self.cell(self.cell_for(&coords)).read().iter().any(|e| &e.point == pt)
+    }
+
+    fn len(&self) -> usize {
+        self.total_points.load(Ordering::Relaxed)
+    }
+
+    // NB we have no per-point allocations to pool, so spare_vectors go untouched
+
+    fn add(&self, point: Point, _spare_vectors: &mut Vec<Vec<Point>>) {
+        let coords = self.space.coords(point.color());
+        let mut cell = self.cell(self.cell_for(&coords)).write();
+
+        if !cell.iter().any(|e| e.point == point) {
+            cell.push(LeafEntry { point, coords });
+            *self.spaces(point.space()).write().entry(*point.space()).or_default() += 1;
+            self.total_points.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn remove(&self, point: Point, _spare_vectors: &mut Vec<Vec<Point>>) {
+        let coords = self.space.coords(point.color());
+        let mut cell = self.cell(self.cell_for(&coords)).write();
+
+        if let Some(idx) = cell.iter().position(|e| e.point == point) {
+            cell.swap_remove(idx);
+            self.total_points.fetch_sub(1, Ordering::Relaxed);
+
+            let mut spaces = self.spaces(point.space()).write();
+            let count = spaces.get_mut(point.space()).expect("Should count the spaces of points we hold");
+            *count -= 1;
+            if *count == 0 {
+                spaces.remove(point.space());
+            }
+        }
+    }
+
+    fn find_nearest(&self, color: &ColorPoint) -> Option<Point> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let coords = self.space.coords(color);
+        let center = self.cell_for(&coords);
+        let ranges = [0, 1, 2].map(|axis| self.offset_range(axis, center[axis]));
+        let last_ring = ranges.iter().map(|&(lo, hi)| (-lo).max(hi)).max().unwrap();
+
+        // Nothing to beat until we find our first point
+        let mut found = false;
+        let mut search = NearestSearch {
+            nearest: Point::new(SpacePoint::zero(), ColorPoint::default()),
+            nearest_dist: i32::MAX,
+            bounds: self.bounds,
+            metric: self.metric,
+            space: self.space,
+        };
+
+        for ring in 0..=last_ring {
+            self.search_ring(&coords, center, ring, &ranges, &mut search, &mut found);
+
+            if found && (search.nearest_dist == 0 || self.rings_cover(center, ring, &ranges, &search.bounds)) {
+                break;
+            }
+        }
+
+        // NB everything might have been removed while we were looking
+        found.then_some(search.nearest)
+    }
+
+    fn find_k_nearest(&self, color: &ColorPoint, k: usize) -> Vec<Point> {
+        // Anything will do until we have k
+        let mut search = RangeSearch::new(self.space.coords(color), i32::MAX, self.space, self.metric);
+        let mut best = KNearest::new(k);
+
+        if k > 0 {
+            self.visit_range(&mut search, |entry, search| {
+                if let Some(dist) = search.distance_to(&entry.coords) {
+                    best.offer(dist, entry.point, search);
+                }
+            });
+        }
+
+        best.into_points()
+    }
+
+    fn find_within(&self, color: &ColorPoint, radius_sq: i32) -> Vec<Point> {
+        let mut search = RangeSearch::new(self.space.coords(color), radius_sq, self.space, self.metric);
+        let mut found = Vec::new();
+
+        self.visit_range(&mut search, |entry, search| {
+            if search.distance_to(&entry.coords).is_some() {
+                found.push(entry.point);
+            }
+        });
+
+        found
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test]
+fn test_uniform_grid_add_remove() {
+    let grid = UniformGrid::new();
+    let mut spare_vectors = Vec::new();
+    assert!(grid.is_empty());
+    assert_eq!(grid.find_nearest(&ColorPoint::new(0, 0, 0)), None);
+
+    let point = Point::new(SpacePoint::new(3, 4), ColorPoint::new(10, 20, 30));
+    grid.add(point, &mut spare_vectors);
+    grid.add(point, &mut spare_vectors);
+    assert_eq!(grid.len(), 1, "Adding the same point again shouldn't do anything");
+
+    assert!(grid.has_point(&point));
+    assert!(grid.has(point.space()));
+    assert_eq!(grid.find_nearest(&ColorPoint::new(255, 255, 255)), Some(point));
+
+    grid.remove(point, &mut spare_vectors);
+    grid.remove(point, &mut spare_vectors);
+    assert!(grid.is_empty());
+    assert!(!grid.has_point(&point));
+    assert_eq!(grid.find_nearest(&ColorPoint::new(10, 20, 30)), None);
+}
+
+#[test]
+fn test_uniform_grid_find_nearest() {
+    // Sparse and dense grids in a few spaces should agree with a brute force search
+    let rng = fastrand::Rng::with_seed(2121);
+    let random_color = || ColorPoint::new(rng.u8(..), rng.u8(..), rng.u8(..));
+
+    let setups = [
+        (ColorSpace::Rgb, ColorMetric::Euclidean),
+        (ColorSpace::Rgb, ColorMetric::Chebyshev),
+        (ColorSpace::Rgb, ColorMetric::weighted(1, 1, 40)),
+        (ColorSpace::CieLab, ColorMetric::Euclidean),
+        (ColorSpace::Hsl, ColorMetric::Euclidean),
+    ];
+
+    for (space, metric) in setups {
+        for count in [3, 1000] {
+            let grid = UniformGrid::new_in(32, space, metric);
+            let mut spare_vectors = Vec::new();
+
+            let placed_points = (0..count).map(|_| random_color()).collect::<Vec<_>>();
+            for (i, color) in placed_points.iter().enumerate() {
+                grid.add(Point::new(SpacePoint::new(i as u32, 0), *color), &mut spare_vectors);
+            }
+
+            for _ in 0..100 {
+                let search_color = random_color();
+                let search_coords = space.coords(&search_color);
+                let control_dist = placed_points.iter()
+                    .map(|color| space.distance(&metric, &space.coords(color), &search_coords))
+                    .min()
+                    .unwrap();
+
+                let nearest = grid.find_nearest(&search_color).expect("Nearest should be found");
+                let nearest_dist = space.distance(&metric, &space.coords(nearest.color()), &search_coords);
+
+                assert_eq!(nearest_dist, control_dist, "{space:?} {metric:?} search among {count} for {search_color:?} found {:?}", nearest.color());
+            }
+        }
+    }
+}
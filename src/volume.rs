@@ -0,0 +1,132 @@
+use crate::{canvas::{Canvas, MAX_DIMENSION}, points::SpacePoint, topology::Topology};
+
+/// A 3D block of voxels, which we lay out on an ordinary canvas as a stack of z slices
+/// Slice z is rows z * height up to (z + 1) * height, so the image, masks and so on all
+/// carry on working, and e.g. 256^3 is a 256x65536 canvas holding every color once
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct Volume {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+}
+
+/// Which nearby voxels count as neighbors
+#[derive(Clone, Copy, Debug, Default, Hash, Eq, PartialEq)]
+pub enum VoxelNeighborhood {
+    /// The 6 voxels sharing a face
+    #[default]
+    Faces,
+    /// All 26 voxels sharing a face, edge or corner
+    Full,
+}
+
+/// Grows through a Volume in three dimensions instead of across the flat canvas
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct VoxelTopology {
+    volume: Volume,
+    neighborhood: VoxelNeighborhood,
+}
+
+impl Volume {
+    pub fn new(width: u32, height: u32, depth: u32) -> Volume {
+        assert!(width > 0 && height > 0 && depth > 0, "Tried to make an empty {width}x{height}x{depth} volume");
+        assert!(
+            width <= MAX_DIMENSION && height as u64 * depth as u64 <= MAX_DIMENSION as u64,
+            "Tried to make a {width}x{height}x{depth} volume (must fit in a {MAX_DIMENSION}x{MAX_DIMENSION} canvas)"
+        );
+
+        Volume { width, height, depth }
+    }
+
+    /// The canvas we lay our slices out on
+    pub fn canvas(&self) -> Canvas {
+        Canvas::new(self.width, self.height * self.depth)
+    }
+
+    pub fn size(&self) -> usize {
+        self.width as usize * self.height as usize * self.depth as usize
+    }
+
+    pub fn contains(&self, x: u32, y: u32, z: u32) -> bool {
+        x < self.width && y < self.height && z < self.depth
+    }
+
+    /// Where a voxel sits on our canvas
+    pub fn space(&self, x: u32, y: u32, z: u32) -> SpacePoint {
+        debug_assert!(self.contains(x, y, z), "Tried to get {x},{y},{z} outside of {self:?}");
+        SpacePoint::new(x, z * self.height + y)
+    }
+
+    /// Inverse of `space`
+    pub fn xyz(&self, space: &SpacePoint) -> (u32, u32, u32) {
+        let (x, row) = space.xy();
+        (x, row % self.height, row / self.height)
+    }
+}
+
+impl VoxelTopology {
+    pub fn new(volume: Volume, neighborhood: VoxelNeighborhood) -> VoxelTopology {
+        VoxelTopology { volume, neighborhood }
+    }
+}
+
+impl Topology for VoxelTopology {
+    fn neighbors(&self, space: &SpacePoint, canvas: &Canvas, ret: &mut Vec<SpacePoint>) {
+        debug_assert_eq!(*canvas, self.volume.canvas(), "Tried to use a voxel topology for {:?} on {canvas}", self.volume);
+
+        let (x, y, z) = self.volume.xyz(space);
+        ret.clear();
+
+        for dz in -1..=1i64 {
+            for dy in -1..=1i64 {
+                for dx in -1..=1i64 {
+                    let steps = dx.abs() + dy.abs() + dz.abs();
+                    if steps == 0 || (self.neighborhood == VoxelNeighborhood::Faces && steps > 1) {
+                        continue;
+                    }
+
+                    let (nx, ny, nz) = (x as i64 + dx, y as i64 + dy, z as i64 + dz);
+                    if nx >= 0 && ny >= 0 && nz >= 0 && self.volume.contains(nx as u32, ny as u32, nz as u32) {
+                        ret.push(self.volume.space(nx as u32, ny as u32, nz as u32));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_volume_layout() {
+    let volume = Volume::new(256, 256, 256);
+    assert_eq!(volume.canvas(), Canvas::new(256, 65536));
+    assert_eq!(volume.size(), 1 << 24);
+
+    for (x, y, z) in [(0, 0, 0), (255, 255, 255), (1, 2, 3), (200, 0, 255)] {
+        assert_eq!(volume.xyz(&volume.space(x, y, z)), (x, y, z));
+    }
+}
+
+#[test]
+fn test_voxel_neighbors() {
+    let volume = Volume::new(4, 4, 4);
+    let canvas = volume.canvas();
+    let mut neighbors = Vec::new();
+
+    let faces = VoxelTopology::new(volume, VoxelNeighborhood::Faces);
+    let full = VoxelTopology::new(volume, VoxelNeighborhood::Full);
+
+    faces.neighbors(&volume.space(1, 1, 1), &canvas, &mut neighbors);
+    assert_eq!(neighbors.len(), 6);
+    assert!(neighbors.contains(&volume.space(1, 1, 0)) && neighbors.contains(&volume.space(1, 1, 2)));
+
+    full.neighbors(&volume.space(1, 1, 1), &canvas, &mut neighbors);
+    assert_eq!(neighbors.len(), 26);
+
+    // Corners, and the slices shouldn't bleed into each other on the canvas
+    faces.neighbors(&volume.space(0, 3, 0), &canvas, &mut neighbors);
+    assert_eq!(neighbors.len(), 3);
+    assert!(!neighbors.contains(&volume.space(0, 0, 1)));
+
+    full.neighbors(&volume.space(3, 3, 3), &canvas, &mut neighbors);
+    assert_eq!(neighbors.len(), 7);
+}